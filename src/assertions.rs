@@ -0,0 +1,379 @@
+use regex::Regex;
+use serde_json::Value;
+
+use crate::model::{
+    AssertionResult, CommandResult, Expectations, JsonCheck, JsonMatcher, JsonType, OutputMatcher,
+};
+
+// Checks the output of a command that ran to completion against the
+// Expectations of its command. Other results are returned unchanged.
+pub fn evaluate_assertions(res: CommandResult) -> CommandResult {
+    match res {
+        CommandResult::StandardResult {
+            command,
+            stdout,
+            stderr,
+            exit_code,
+            timing,
+            ..
+        } => {
+            let assertions = command
+                .expect
+                .stdout
+                .iter()
+                .map(|m| check_output("stdout", &stdout, m))
+                .chain(
+                    command
+                        .expect
+                        .stderr
+                        .iter()
+                        .map(|m| check_output("stderr", &stderr, m)),
+                )
+                .chain(check_json_output(&stdout, &command.expect.json))
+                .collect();
+            CommandResult::StandardResult {
+                command,
+                stdout,
+                stderr,
+                exit_code,
+                assertions,
+                timing,
+            }
+        }
+        res => res,
+    }
+}
+
+// What a command is checked against, like `stdout contains "ok"`, for a
+// command that hasn't run
+pub fn describe_expectations(expect: &Expectations) -> Vec<String> {
+    let codes: Vec<String> = expect.exit_codes.iter().map(|c| c.to_string()).collect();
+    let outputs = expect
+        .stdout
+        .iter()
+        .map(|m| check_output("stdout", "", m))
+        .chain(expect.stderr.iter().map(|m| check_output("stderr", "", m)))
+        .map(|a| match a.expected.as_str() {
+            "" | "(any output)" => a.assertion,
+            expected => format!("{} {:?}", a.assertion, expected),
+        });
+    let json = expect
+        .json
+        .iter()
+        .map(|m| check_json(&Err(String::new()), m))
+        .map(|a| match a.expected.as_str() {
+            "(any value)" | "(missing)" => a.assertion,
+            expected => format!("{} {}", a.assertion, expected),
+        });
+    std::iter::once(format!("exit code {}", codes.join(" or ")))
+        .chain(outputs)
+        .chain(json)
+        .collect()
+}
+
+pub fn check_output(stream: &str, output: &str, matcher: &OutputMatcher) -> AssertionResult {
+    match matcher {
+        OutputMatcher::Contains(expected) => AssertionResult {
+            assertion: format!("{} contains", stream),
+            expected: expected.clone(),
+            actual: output.to_string(),
+            success: output.contains(expected.as_str()),
+        },
+        OutputMatcher::Equals(expected) => AssertionResult {
+            assertion: format!("{} equals", stream),
+            expected: expected.clone(),
+            actual: output.to_string(),
+            success: trim_newlines(output) == trim_newlines(expected),
+        },
+        OutputMatcher::Matches(pattern) => match Regex::new(pattern) {
+            Ok(regex) => AssertionResult {
+                assertion: format!("{} matches", stream),
+                expected: pattern.clone(),
+                actual: output.to_string(),
+                success: regex.is_match(output),
+            },
+            Err(err) => AssertionResult {
+                assertion: format!("{} matches", stream),
+                expected: pattern.clone(),
+                actual: format!("Invalid regex: {}", err),
+                success: false,
+            },
+        },
+        OutputMatcher::Empty(true) => AssertionResult {
+            assertion: format!("{} is empty", stream),
+            expected: String::new(),
+            actual: output.to_string(),
+            success: output.is_empty(),
+        },
+        OutputMatcher::Empty(false) => AssertionResult {
+            assertion: format!("{} is not empty", stream),
+            expected: "(any output)".to_string(),
+            actual: output.to_string(),
+            success: !output.is_empty(),
+        },
+    }
+}
+
+// Stdout is only parsed when there is something to check
+fn check_json_output(stdout: &str, matchers: &[JsonMatcher]) -> Vec<AssertionResult> {
+    if matchers.is_empty() {
+        return Vec::new();
+    }
+    let json = serde_json::from_str::<Value>(stdout).map_err(|e| e.to_string());
+    matchers.iter().map(|m| check_json(&json, m)).collect()
+}
+
+pub fn check_json(json: &Result<Value, String>, matcher: &JsonMatcher) -> AssertionResult {
+    let (name, expected) = match &matcher.check {
+        JsonCheck::Equals(value) => ("equals", value.to_string()),
+        JsonCheck::Exists(true) => ("exists", "(any value)".to_string()),
+        JsonCheck::Exists(false) => ("does not exist", "(missing)".to_string()),
+        JsonCheck::Type(json_type) => ("type", json_type_name(json_type).to_string()),
+        JsonCheck::Length(length) => ("length", length.to_string()),
+        JsonCheck::GreaterThan(n) => ("greater than", n.to_string()),
+        JsonCheck::GreaterOrEqual(n) => ("greater or equal", n.to_string()),
+        JsonCheck::LessThan(n) => ("less than", n.to_string()),
+        JsonCheck::LessOrEqual(n) => ("less or equal", n.to_string()),
+    };
+    let assertion = format!("json {} {}", matcher.path, name);
+
+    let json = match json {
+        Ok(json) => json,
+        Err(err) => {
+            return AssertionResult {
+                assertion,
+                expected,
+                actual: format!("stdout is not valid JSON: {}", err),
+                success: false,
+            }
+        }
+    };
+
+    let found = json.pointer(&matcher.pointer);
+    let number = found.and_then(Value::as_f64);
+    let success = match &matcher.check {
+        JsonCheck::Equals(Value::Number(n)) => number.is_some() && number == n.as_f64(),
+        JsonCheck::Equals(value) => found == Some(value),
+        JsonCheck::Exists(exists) => found.is_some() == *exists,
+        JsonCheck::Type(json_type) => found.map(value_type).as_ref() == Some(json_type),
+        JsonCheck::Length(length) => found.and_then(value_length) == Some(*length),
+        JsonCheck::GreaterThan(n) => number.map(|v| v > *n).unwrap_or(false),
+        JsonCheck::GreaterOrEqual(n) => number.map(|v| v >= *n).unwrap_or(false),
+        JsonCheck::LessThan(n) => number.map(|v| v < *n).unwrap_or(false),
+        JsonCheck::LessOrEqual(n) => number.map(|v| v <= *n).unwrap_or(false),
+    };
+
+    AssertionResult {
+        assertion,
+        expected,
+        actual: match (found, &matcher.check) {
+            (Some(value), JsonCheck::Type(_)) => json_type_name(&value_type(value)).to_string(),
+            (Some(value), JsonCheck::Length(_)) => {
+                value_length(value).map_or_else(|| value.to_string(), |len| len.to_string())
+            }
+            (Some(value), _) => value.to_string(),
+            (None, _) => "(missing)".to_string(),
+        },
+        success,
+    }
+}
+
+fn value_type(value: &Value) -> JsonType {
+    match value {
+        Value::Null => JsonType::Null,
+        Value::Bool(_) => JsonType::Bool,
+        Value::Number(_) => JsonType::Number,
+        Value::String(_) => JsonType::String,
+        Value::Array(_) => JsonType::Array,
+        Value::Object(_) => JsonType::Object,
+    }
+}
+
+fn value_length(value: &Value) -> Option<usize> {
+    match value {
+        Value::String(s) => Some(s.chars().count()),
+        Value::Array(a) => Some(a.len()),
+        Value::Object(o) => Some(o.len()),
+        _ => None,
+    }
+}
+
+pub fn json_type_name(json_type: &JsonType) -> &'static str {
+    match json_type {
+        JsonType::Null => "null",
+        JsonType::Bool => "bool",
+        JsonType::Number => "number",
+        JsonType::String => "string",
+        JsonType::Array => "array",
+        JsonType::Object => "object",
+    }
+}
+
+// Converts a JSONPath without wildcards, filters or slices, like
+// `$.items[0]['first name']`, to a JSON Pointer, `/items/0/first name`.
+// Paths starting with `/` are already JSON Pointers.
+pub fn json_path_to_pointer(path: &str) -> Result<String, String> {
+    if path.is_empty() || path.starts_with('/') {
+        return Ok(path.to_string());
+    }
+    let unsupported = || format!("Unsupported JSON path: {}", path);
+
+    let mut rest = path
+        .strip_prefix('$')
+        .ok_or_else(|| format!("JSON path must start with '$' or '/': {}", path))?;
+    let mut pointer = String::new();
+    while !rest.is_empty() {
+        let key = if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            let key = &r[..end];
+            rest = &r[end..];
+            key
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']').ok_or_else(unsupported)?;
+            let inner = r[..end].trim();
+            rest = &r[end + 1..];
+            if inner.len() >= 2
+                && ((inner.starts_with('\'') && inner.ends_with('\''))
+                    || (inner.starts_with('"') && inner.ends_with('"')))
+            {
+                &inner[1..inner.len() - 1]
+            } else if inner.parse::<usize>().is_ok() {
+                inner
+            } else {
+                return Err(unsupported());
+            }
+        } else {
+            return Err(unsupported());
+        };
+        if key.is_empty() || key == "*" {
+            return Err(unsupported());
+        }
+        pointer.push('/');
+        pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+    }
+    Ok(pointer)
+}
+
+fn trim_newlines(s: &str) -> &str {
+    s.trim_end_matches(['\n', '\r'])
+}
+
+#[cfg(test)]
+use crate::model::{ExecutableCommand, Timing};
+
+#[test]
+fn t_check_output() {
+    let check = |matcher| check_output("stdout", "Hello World\n", &matcher).success;
+
+    assert!(check(OutputMatcher::Contains("World".to_string())));
+    assert!(!check(OutputMatcher::Contains("world".to_string())));
+    assert!(check(OutputMatcher::Equals("Hello World".to_string())));
+    assert!(!check(OutputMatcher::Equals("Hello".to_string())));
+    assert!(check(OutputMatcher::Matches(
+        "(?m)^Hello \\w+$".to_string()
+    )));
+    assert!(!check(OutputMatcher::Matches("^World".to_string())));
+    assert!(!check(OutputMatcher::Matches("(".to_string())));
+    assert!(!check(OutputMatcher::Empty(true)));
+    assert!(check(OutputMatcher::Empty(false)));
+}
+
+#[test]
+fn t_evaluate_assertions() {
+    let command = ExecutableCommand {
+        cmd: "echo Hello; exit 3".to_string(),
+        expect: Expectations {
+            exit_codes: vec![0, 3],
+            stdout: vec![OutputMatcher::Equals("Goodbye".to_string())],
+            stderr: vec![OutputMatcher::Empty(true)],
+            json: vec![JsonMatcher {
+                path: "$.greeting".to_string(),
+                pointer: "/greeting".to_string(),
+                check: JsonCheck::Exists(true),
+            }],
+        },
+        ..Default::default()
+    };
+
+    let res = evaluate_assertions(CommandResult::StandardResult {
+        command,
+        stdout: "Hello\n".to_string(),
+        stderr: String::new(),
+        exit_code: 3,
+        assertions: Vec::new(),
+        timing: Timing::default(),
+    });
+
+    match &res {
+        CommandResult::StandardResult { assertions, .. } => {
+            assert_eq!(assertions.len(), 3);
+            assert_eq!(
+                assertions[0].message(),
+                "stdout equals: expected \"Goodbye\", actual \"Hello\\n\""
+            );
+            assert!(assertions[1].success);
+            assert!(assertions[2].actual.starts_with("stdout is not valid JSON"));
+        }
+        _ => panic!("Fail"),
+    }
+    assert!(!res.success());
+}
+
+#[test]
+fn t_json_path_to_pointer() {
+    assert_eq!(json_path_to_pointer("$"), Ok("".to_string()));
+    assert_eq!(json_path_to_pointer("$.a.b"), Ok("/a/b".to_string()));
+    assert_eq!(
+        json_path_to_pointer("$.items[0]['first name']"),
+        Ok("/items/0/first name".to_string())
+    );
+    assert_eq!(json_path_to_pointer("$[\"a/b\"]"), Ok("/a~1b".to_string()));
+    assert_eq!(json_path_to_pointer("/items/0"), Ok("/items/0".to_string()));
+    assert!(json_path_to_pointer("$.items[*]").is_err());
+    assert!(json_path_to_pointer("$..name").is_err());
+    assert!(json_path_to_pointer("items").is_err());
+}
+
+#[test]
+fn t_check_json() {
+    let json = Ok(serde_json::json!({
+        "status": "UP",
+        "count": 3,
+        "items": [1, 2, 3],
+    }));
+    let check = |path: &str, check| {
+        let matcher = JsonMatcher {
+            path: path.to_string(),
+            pointer: json_path_to_pointer(path).unwrap(),
+            check,
+        };
+        check_json(&json, &matcher)
+    };
+
+    assert!(check("$.status", JsonCheck::Equals(serde_json::json!("UP"))).success);
+    assert!(check("$.count", JsonCheck::Equals(serde_json::json!(3.0))).success);
+    assert!(check("$.status", JsonCheck::Exists(true)).success);
+    assert!(check("$.missing", JsonCheck::Exists(false)).success);
+    assert!(check("$.items", JsonCheck::Type(JsonType::Array)).success);
+    assert!(check("$.items", JsonCheck::Length(3)).success);
+    assert!(check("$.items[2]", JsonCheck::GreaterThan(2.0)).success);
+    assert!(check("$.count", JsonCheck::LessOrEqual(3.0)).success);
+    assert!(!check("$.status", JsonCheck::LessThan(3.0)).success);
+
+    let res = check("/count", JsonCheck::GreaterOrEqual(10.0));
+    assert_eq!(
+        res.message(),
+        "json /count greater or equal: expected \"10\", actual \"3\""
+    );
+
+    let res = check_json(
+        &serde_json::from_str::<Value>("not json").map_err(|e| e.to_string()),
+        &JsonMatcher {
+            path: "$".to_string(),
+            pointer: "".to_string(),
+            check: JsonCheck::Exists(true),
+        },
+    );
+    assert!(!res.success);
+    assert!(res.actual.starts_with("stdout is not valid JSON"));
+}
@@ -0,0 +1,255 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::FcheckError;
+use crate::filter::{filter_tests, TestFilter};
+use crate::model::{ProcessingKind, ProcessingModule, Variables};
+use crate::parser::{
+    default_max_parallel, file_extension_to_filetype, find_config_files, prepare_config_file,
+    CONFIG_FILE_SUFFIXES,
+};
+
+// The config files to run, directories are searched for config files
+pub fn resolve_config_paths(paths: &[&str]) -> Result<Vec<PathBuf>, FcheckError> {
+    let mut config_paths = Vec::new();
+    for path in paths {
+        let config_path = Path::new(path);
+        if !config_path.exists() {
+            return Err(FcheckError::ConfigNotFound(path.to_string()));
+        }
+        if config_path.is_dir() {
+            let found = find_config_files(config_path).map_err(|error| FcheckError::Io {
+                context: format!("Failed to search {} for config files", path),
+                error,
+            })?;
+            if found.is_empty() {
+                return Err(FcheckError::UnsupportedFormat(format!(
+                    "No config files found in {}. Config files are named like *{}",
+                    path,
+                    CONFIG_FILE_SUFFIXES.join(" or *")
+                )));
+            }
+            config_paths.extend(found);
+        } else {
+            config_paths.push(config_path.to_path_buf());
+        }
+    }
+    Ok(config_paths)
+}
+
+// Reads and parses a config file, its format is taken from the extension
+pub fn load_config_file(
+    path: &Path,
+    var_overrides: &Variables,
+) -> Result<ProcessingModule, FcheckError> {
+    let file = path.to_string_lossy().to_string();
+    let file_type = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .and_then(file_extension_to_filetype)
+        .ok_or_else(|| {
+            FcheckError::UnsupportedFormat(format!(
+                "Config file {} has an unsupported extension. Valid extensions: .toml, .dhall",
+                file
+            ))
+        })?;
+
+    let contents = fs::read_to_string(path).map_err(|error| FcheckError::Io {
+        context: format!("Failed to read config file {}", file),
+        error,
+    })?;
+
+    prepare_config_file(file_type, path, contents.clone(), var_overrides).map_err(|error| {
+        FcheckError::Parse {
+            file,
+            source: contents,
+            error,
+        }
+    })
+}
+
+// Settings of the command line, applied to every config file that is loaded
+#[derive(Debug, Default)]
+pub struct LoadOptions {
+    // Replace the variables of the same name in [vars]
+    pub var_overrides: Variables,
+    pub filter: TestFilter,
+    // Milliseconds, for the commands that get no timeout from the config
+    pub timeout: Option<u64>,
+    pub fail_fast: bool,
+    // Runs the tests of every file in parallel, up to `max_parallel` at a
+    // time, or as many as there are CPUs
+    pub parallel: bool,
+    pub max_parallel: Option<usize>,
+}
+
+// Loads the config files and directories in `paths`, named by their path,
+// with the tests selected and the settings of `options` applied
+pub fn load_modules(
+    paths: &[&str],
+    options: &LoadOptions,
+) -> Result<Vec<(String, ProcessingModule)>, FcheckError> {
+    let mut modules = Vec::new();
+    for config_path in resolve_config_paths(paths)?.iter() {
+        let mut module = load_config_file(config_path, &options.var_overrides)?;
+        filter_tests(&mut module.tests, &options.filter);
+        if let Some(timeout) = options.timeout {
+            module.set_default_timeout(timeout);
+        }
+        if options.fail_fast {
+            module.tests.fail_fast = true;
+        }
+
+        match (options.max_parallel, &module.tests.processing_kind) {
+            (Some(max), _) => module.tests.processing_kind = ProcessingKind::Parallel(max),
+            (None, ProcessingKind::Serial) if options.parallel => {
+                module.tests.processing_kind = ProcessingKind::Parallel(default_max_parallel())
+            }
+            _ => (),
+        }
+
+        modules.push((config_path.to_string_lossy().to_string(), module));
+    }
+    Ok(modules)
+}
+
+#[cfg(test)]
+use crate::model::{CommandResult, ExecutableCommand, Timing};
+
+#[test]
+fn t_load_and_run_with_stub() {
+    use crate::output_formatter::format_module;
+    use crate::processor::run_processingmodule;
+
+    let dir = std::env::temp_dir().join(format!("fcheck-config-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("api.fcheck.toml"),
+        r#"
+        version = "3"
+
+        [[test]]
+        name = "list"
+        [[test.command]]
+        cmd = "curl localhost:8080/items"
+
+        [[test]]
+        name = "get"
+        [[test.command]]
+        cmd = "curl localhost:8080/items/1"
+    "#,
+    )
+    .unwrap();
+
+    let paths = resolve_config_paths(&[dir.to_str().unwrap()]).unwrap();
+    let module = load_config_file(&paths[0], &Variables::new()).unwrap();
+
+    // Nothing is run, every command succeeds with its command line as stdout
+    let res = run_processingmodule(
+        &|cmd: &ExecutableCommand| CommandResult::StandardResult {
+            command: cmd.clone(),
+            stdout: cmd.cmd.clone(),
+            stderr: String::new(),
+            exit_code: 0,
+            assertions: Vec::new(),
+            timing: Timing::default(),
+        },
+        &|_| (),
+        &module,
+    );
+    assert!(res.success());
+    assert_eq!(res.tests.as_ref().unwrap().sets.len(), 2);
+    assert!(format_module(&res).contains("\"stdout\": \"curl localhost:8080/items/1\""));
+
+    assert!(matches!(
+        resolve_config_paths(&["./examples/missing.toml"]),
+        Err(FcheckError::ConfigNotFound(_))
+    ));
+    assert!(matches!(
+        load_config_file(Path::new("./Cargo.toml"), &Variables::new()),
+        Err(FcheckError::Parse { .. })
+    ));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn t_load_modules_with_options() {
+    let dir = std::env::temp_dir().join(format!("fcheck-load-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("api.fcheck.toml"),
+        r#"
+        version = "3"
+
+        [[test]]
+        name = "list"
+        [[test.command]]
+        cmd = "curl ${host}/items"
+
+        [[test]]
+        name = "get"
+        [[test.command]]
+        cmd = "curl ${host}/items/1"
+        timeout = 500
+
+        [vars]
+        host = "localhost"
+    "#,
+    )
+    .unwrap();
+
+    let mut var_overrides = Variables::new();
+    var_overrides.insert("host".to_string(), "example.com".to_string());
+    let options = LoadOptions {
+        var_overrides,
+        filter: TestFilter {
+            skip: vec![crate::filter::NamePattern::new("list").unwrap()],
+            ..Default::default()
+        },
+        timeout: Some(1000),
+        fail_fast: true,
+        max_parallel: Some(3),
+        ..Default::default()
+    };
+    let modules = load_modules(&[dir.to_str().unwrap()], &options).unwrap();
+
+    assert_eq!(modules.len(), 1);
+    let tests = &modules[0].1.tests;
+    assert!(tests.sets[0].skip.is_some());
+    assert_eq!(tests.sets[1].commands[0].cmd, "curl example.com/items/1");
+    assert_eq!(tests.sets[0].commands[0].timeout, Some(1000));
+    assert_eq!(tests.sets[1].commands[0].timeout, Some(500));
+    assert!(tests.fail_fast);
+    assert_eq!(tests.processing_kind, ProcessingKind::Parallel(3));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn t_load_dhall_with_relative_import() {
+    let dir = std::env::temp_dir().join(format!("fcheck-dhall-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("common.dhall"), r#"{ host = "localhost:8080" }"#).unwrap();
+    fs::write(
+        dir.join("api.fcheck.dhall"),
+        r#"
+        let common = ./common.dhall
+
+        in  { version = "3"
+            , test = [ { commands = [ { command = "curl ${common.host}/items" } ] } ]
+            }
+    "#,
+    )
+    .unwrap();
+
+    // The import is next to the config, not in the working directory
+    let module = load_config_file(&dir.join("api.fcheck.dhall"), &Variables::new()).unwrap();
+    assert_eq!(
+        module.tests.sets[0].commands[0].cmd,
+        "curl localhost:8080/items"
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,653 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::model::{
+    CommandResult, CommandSet, CommandSetResult, ExecutableCommand, ProcessingModuleResult,
+    RunResult, ServiceResult,
+};
+use crate::processor::{ProcessingEvent, RunEvent};
+
+// Human readable progress, written to the console while the module runs.
+// Output of a command is only shown when the command fails.
+
+pub struct ConsoleReporter {
+    color: bool,
+    labels: ModuleLabels,
+    // Keeps the lines of an event together when sets run in parallel
+    lock: Mutex<()>,
+}
+
+// How the output of each config file is told apart, when a run has several
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModuleLabels {
+    None,
+    // A header before the output of each file, when they run one after the other
+    Headers,
+    // The file on every line, when they run in parallel
+    Prefix,
+}
+
+enum Color {
+    Red,
+    Green,
+    Yellow,
+    Cyan,
+    Dim,
+}
+
+impl ConsoleReporter {
+    pub fn new(color: bool) -> ConsoleReporter {
+        ConsoleReporter {
+            color,
+            labels: ModuleLabels::None,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn with_module_labels(self, labels: ModuleLabels) -> ConsoleReporter {
+        ConsoleReporter { labels, ..self }
+    }
+
+    pub fn on_run_event(&self, event: &RunEvent) {
+        self.print(&self.format_run_event(event));
+    }
+
+    pub fn print_run_summary(&self, run: &RunResult) {
+        self.print(&self.format_run_summary(run));
+    }
+
+    pub fn print_slowest(&self, run: &RunResult, count: usize) {
+        self.print(&self.format_slowest(run, count));
+    }
+
+    fn format_run_event(&self, event: &RunEvent) -> String {
+        let header =
+            |file: &str| format!("\n{}\n", self.paint(Color::Cyan, &format!("==> {}", file)));
+        match (event, self.labels) {
+            (RunEvent::ModuleStarted(file), ModuleLabels::Headers) => header(file),
+            (RunEvent::ModuleStarted(_), _) => String::new(),
+            (RunEvent::Processing(file, event), ModuleLabels::Prefix) => self
+                .format_event(event)
+                .lines()
+                .map(|line| {
+                    format!(
+                        "{} {}\n",
+                        self.paint(Color::Dim, &format!("[{}]", file)),
+                        line
+                    )
+                })
+                .collect(),
+            (RunEvent::Processing(_, event), _) => self.format_event(event),
+            // The summary is kept together, under the name of its file
+            (RunEvent::ModuleFinished(res), ModuleLabels::Prefix) => {
+                header(&res.file) + &self.format_summary(&res.result)
+            }
+            (RunEvent::ModuleFinished(res), _) => self.format_summary(&res.result),
+        }
+    }
+
+    fn format_run_summary(&self, run: &RunResult) -> String {
+        let mut text = String::from("\nRun Summary\n");
+        for module in run.modules.iter() {
+            text.push_str(&format!(
+                "  {} {}\n",
+                self.status(module.result.success()),
+                module.file
+            ));
+        }
+        let passed = run.modules.iter().filter(|m| m.result.success()).count();
+        let failed = run.modules.len() - passed;
+        let result = if run.success() {
+            self.paint(Color::Green, "SUCCESS")
+        } else {
+            self.paint(Color::Red, "FAILURE")
+        };
+        text.push_str(&format!(
+            "\n{} config files, {} passed, {} failed\n{}\n",
+            run.modules.len(),
+            passed,
+            failed,
+            result
+        ));
+        text
+    }
+
+    // The commands that took the longest, to find what makes a run slow
+    fn format_slowest(&self, run: &RunResult, count: usize) -> String {
+        let slowest = run.slowest_commands(count);
+        if slowest.is_empty() {
+            return String::new();
+        }
+        let mut text = String::from("\nSlowest Commands\n");
+        for (file, set, res) in slowest {
+            let file = if run.modules.len() > 1 {
+                format!("{} ", self.paint(Color::Dim, &format!("[{}]", file)))
+            } else {
+                String::new()
+            };
+            text.push_str(&format!(
+                "  {:>8}  {}{} > {}\n",
+                format_duration(res.timing().duration),
+                file,
+                set_name(set),
+                command_name(res.command())
+            ));
+        }
+        text
+    }
+
+    fn print(&self, text: &str) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn paint(&self, color: Color, text: &str) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        let code = match color {
+            Color::Red => "31",
+            Color::Green => "32",
+            Color::Yellow => "33",
+            Color::Cyan => "36",
+            Color::Dim => "2",
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+
+    fn status(&self, success: bool) -> String {
+        if success {
+            self.paint(Color::Green, "PASS")
+        } else {
+            self.paint(Color::Red, "FAIL")
+        }
+    }
+
+    // Failures that are only about time are told apart, a slow service may
+    // call for a different fix than a broken one
+    fn result_status(&self, success: bool, slow: bool, allowed_failure: bool) -> String {
+        if allowed_failure {
+            self.paint(Color::Yellow, "WARN")
+        } else if slow {
+            self.paint(Color::Red, "SLOW")
+        } else {
+            self.status(success)
+        }
+    }
+
+    fn format_warning(&self, warning: Option<String>) -> String {
+        match warning {
+            Some(warning) => format!(
+                "       {}\n",
+                self.paint(Color::Yellow, &format!("warning: {}", warning))
+            ),
+            None => String::new(),
+        }
+    }
+
+    fn format_event(&self, event: &ProcessingEvent) -> String {
+        match event {
+            ProcessingEvent::SetStarted(set) => {
+                format!("{} {}\n", self.paint(Color::Cyan, "RUN "), set_name(set))
+            }
+            ProcessingEvent::SetSkipped(set) => format!(
+                "{} {} {}\n",
+                self.paint(Color::Yellow, "SKIP"),
+                set_name(set),
+                self.paint(
+                    Color::Dim,
+                    &format!("({})", set.skip.clone().unwrap_or_default())
+                )
+            ),
+            ProcessingEvent::CommandStarted(set, cmd) => format!(
+                "  {} {} > {}\n",
+                self.paint(Color::Dim, "... "),
+                set_name(set),
+                command_name(cmd)
+            ),
+            ProcessingEvent::CommandFinished(
+                set,
+                CommandResult::NotRun {
+                    command,
+                    reason,
+                    aborted,
+                    ..
+                },
+                _,
+            ) => {
+                format!(
+                    "  {} {} > {} {}\n",
+                    if *aborted {
+                        self.paint(Color::Red, "STOP")
+                    } else {
+                        self.paint(Color::Yellow, "SKIP")
+                    },
+                    set_name(set),
+                    command_name(command),
+                    self.paint(Color::Dim, &format!("({})", reason))
+                )
+            }
+            ProcessingEvent::CommandFinished(set, res, duration) => {
+                let attempts = match res.attempt_count() {
+                    1 => String::new(),
+                    n => format!(", {} attempts", n),
+                };
+                let mut text = format!(
+                    "  {} {} > {} {}\n",
+                    self.result_status(res.success(), res.slow(), res.allowed_failure()),
+                    set_name(set),
+                    command_name(res.command()),
+                    self.paint(
+                        Color::Dim,
+                        &format!("({}{})", format_duration(*duration), attempts)
+                    )
+                );
+                if !res.success() {
+                    text.push_str(&self.format_failure(res));
+                }
+                text.push_str(&self.format_warning(res.duration_warning()));
+                text
+            }
+            ProcessingEvent::SetFinished(res, _) if res.not_run().is_some() => format!(
+                "{} {} {}\n",
+                self.paint(Color::Red, "STOP"),
+                set_name(&res.set),
+                self.paint(
+                    Color::Dim,
+                    &format!("({})", res.not_run().unwrap_or_default())
+                )
+            ),
+            ProcessingEvent::SetFinished(res, duration) => {
+                let mut text = format!(
+                    "{} {} {}\n",
+                    self.result_status(res.success(), res.slow(), res.allowed_failure()),
+                    set_name(&res.set),
+                    self.paint(Color::Dim, &format!("({})", format_duration(*duration)))
+                );
+                // Only its own budget, slow commands said why when they finished
+                if let Some(reason) = res.over_max_duration() {
+                    text.push_str(&format!("       {}\n", self.paint(Color::Red, &reason)));
+                }
+                text.push_str(&self.format_warning(res.duration_warning()));
+                text
+            }
+            ProcessingEvent::CommandRetrying(set, res, attempt, delay) => format!(
+                "  {} {} > {} {}\n",
+                self.paint(Color::Yellow, "RTRY"),
+                set_name(set),
+                command_name(res.command()),
+                self.paint(
+                    Color::Dim,
+                    &format!(
+                        "(attempt {} failed, retrying in {})",
+                        attempt,
+                        format_duration(*delay)
+                    )
+                )
+            ),
+            ProcessingEvent::ServiceStarting(service) => format!(
+                "{} {} > starting\n",
+                self.paint(Color::Cyan, "SVC "),
+                service.name
+            ),
+            ProcessingEvent::ServiceReady(service, duration) => format!(
+                "{} {} > ready {}\n",
+                self.paint(Color::Green, "UP  "),
+                service.name,
+                self.paint(Color::Dim, &format!("({})", format_duration(*duration)))
+            ),
+            ProcessingEvent::ServiceStopped(res) => self.format_service_stopped(res),
+        }
+    }
+
+    fn format_service_stopped(&self, res: &ServiceResult) -> String {
+        match &res.error {
+            None => format!(
+                "{} {} > {}\n",
+                self.paint(Color::Dim, "DOWN"),
+                res.service.name,
+                res.exit_status
+            ),
+            Some(error) => {
+                let mut text = format!(
+                    "{} {} > {}\n",
+                    self.paint(Color::Red, "FAIL"),
+                    res.service.name,
+                    error
+                );
+                text.push_str(&self.format_output(&res.stdout, &res.stderr));
+                text
+            }
+        }
+    }
+
+    fn format_failure(&self, res: &CommandResult) -> String {
+        let mut text = String::new();
+        let reason = match res {
+            CommandResult::OsError { error, .. } | CommandResult::NotRun { reason: error, .. } => {
+                error.clone()
+            }
+            CommandResult::RuntimeError { error, .. } => error.clone(),
+            CommandResult::Timeout {
+                command,
+                signal,
+                reason: Some(reason),
+                ..
+            } => format!(
+                "{}, stopped after {}ms with {}",
+                reason,
+                command.timeout.unwrap_or(0),
+                signal
+            ),
+            CommandResult::Timeout {
+                command, signal, ..
+            } => format!(
+                "Timed out after {}ms, stopped with {}",
+                command.timeout.unwrap_or(0),
+                signal
+            ),
+            CommandResult::IrregularExitCode { exit_code, .. } => {
+                format!("Exited with {}", exit_code)
+            }
+            CommandResult::StandardResult {
+                command, exit_code, ..
+            } if !command.expect.exit_codes.contains(exit_code) => {
+                format!(
+                    "Exited with code {}, expected {}",
+                    exit_code,
+                    format_exit_codes(&command.expect.exit_codes)
+                )
+            }
+            CommandResult::StandardResult { .. } if res.slow() => {
+                res.slow_reason().unwrap_or_default()
+            }
+            CommandResult::StandardResult { assertions, .. } => assertions
+                .iter()
+                .filter(|a| !a.success)
+                .map(|a| a.message())
+                .collect::<Vec<_>>()
+                .join("\n       "),
+            CommandResult::ServiceCrashed {
+                service,
+                exit_status,
+                ..
+            } => format!("Service '{}' {}", service, exit_status),
+            CommandResult::Retried { .. } => return self.format_failure(res.last_attempt()),
+        };
+        text.push_str(&format!("       {}\n", self.paint(Color::Red, &reason)));
+        text.push_str(&self.format_output(res.stdout(), res.stderr()));
+        text
+    }
+
+    fn format_output(&self, stdout: &str, stderr: &str) -> String {
+        let mut text = String::new();
+        for (label, output) in [("stdout", stdout), ("stderr", stderr)].iter() {
+            if output.is_empty() {
+                continue;
+            }
+            text.push_str(&format!(
+                "       {}\n",
+                self.paint(Color::Yellow, &format!("--- {} ---", label))
+            ));
+            for line in output.lines() {
+                text.push_str(&format!("       {}\n", line));
+            }
+        }
+        text
+    }
+
+    fn format_summary(&self, module: &ProcessingModuleResult) -> String {
+        let set_status = |res: &Option<CommandSetResult>, skipped: Option<String>| match res {
+            Some(res) if res.success() => self.paint(Color::Green, "success"),
+            Some(_) => self.paint(Color::Red, "failure"),
+            None => self.paint(
+                Color::Yellow,
+                &format!("skipped, {}", skipped.unwrap_or_default()),
+            ),
+        };
+        let tests = match &module.tests {
+            Some(tests) => {
+                let skipped = tests.sets.iter().filter(|s| s.skipped()).count();
+                let passed = tests.sets.iter().filter(|s| s.success()).count() - skipped;
+                let not_run = tests.sets.iter().filter(|s| s.not_run().is_some()).count();
+                let allowed = tests
+                    .sets
+                    .iter()
+                    .filter(|s| s.allowed_failure() && s.not_run().is_none())
+                    .count();
+                let failed = tests.sets.len() - passed - skipped - allowed - not_run;
+                let mut text = format!(
+                    "{}, {}",
+                    self.paint(Color::Green, &format!("{} passed", passed)),
+                    if failed > 0 {
+                        self.paint(Color::Red, &format!("{} failed", failed))
+                    } else {
+                        format!("{} failed", failed)
+                    }
+                );
+                if allowed > 0 {
+                    text.push_str(&format!(
+                        ", {}",
+                        self.paint(Color::Yellow, &format!("{} failed as allowed", allowed))
+                    ));
+                }
+                if skipped > 0 {
+                    text.push_str(&format!(
+                        ", {}",
+                        self.paint(Color::Yellow, &format!("{} skipped", skipped))
+                    ));
+                }
+                if not_run > 0 {
+                    text.push_str(&format!(
+                        ", {}",
+                        self.paint(Color::Red, &format!("{} not run", not_run))
+                    ));
+                }
+                text
+            }
+            None => self.paint(
+                Color::Yellow,
+                &format!(
+                    "{} skipped, {}",
+                    module.module.tests.sets.len(),
+                    module.tests_skipped_reason().unwrap_or_default()
+                ),
+            ),
+        };
+        let services = if module.services.is_empty() {
+            String::new()
+        } else {
+            let failed = module.services.iter().any(|s| !s.success());
+            format!(
+                "  Services  {}\n",
+                if failed {
+                    self.paint(Color::Red, "failure")
+                } else {
+                    self.paint(Color::Green, "success")
+                }
+            )
+        };
+        let result = if module.success() {
+            self.paint(Color::Green, "SUCCESS")
+        } else {
+            self.paint(Color::Red, "FAILURE")
+        };
+
+        format!(
+            "\nSummary\n  Setup     {}\n{}  Tests     {}\n  Teardown  {}\n\n{}\n",
+            set_status(&Some(module.setup.clone()), None),
+            services,
+            tests,
+            set_status(&module.teardown, module.teardown_skipped_reason()),
+            result
+        )
+    }
+}
+
+fn set_name(set: &CommandSet) -> String {
+    set.name.clone().unwrap_or_else(|| "(unnamed)".to_string())
+}
+
+fn command_name(cmd: &ExecutableCommand) -> String {
+    match &cmd.name {
+        Some(name) => name.clone(),
+        None => {
+            let line = cmd.cmd.trim().lines().next().unwrap_or("");
+            if line.chars().count() > 60 {
+                format!("{}...", line.chars().take(57).collect::<String>())
+            } else {
+                line.to_string()
+            }
+        }
+    }
+}
+
+fn format_exit_codes(codes: &[u32]) -> String {
+    codes
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration.as_millis() < 1000 {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.2}s", duration.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+use crate::model::{ProcessingKind, Shell, Timing};
+
+#[cfg(test)]
+fn test_set(cmd: &str) -> CommandSet {
+    CommandSet {
+        name: Some("test 1".to_string()),
+        commands: vec![ExecutableCommand {
+            name: Option::None,
+            description: Option::None,
+            timeout: None,
+            shell: Shell::default(),
+            cmd: cmd.to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn t_console_command_output_only_on_failure() {
+    let reporter = ConsoleReporter::new(false);
+    let set = test_set("echo Hello");
+    let res = |exit_code| CommandResult::StandardResult {
+        command: set.commands[0].clone(),
+        stdout: "Hello\n".to_string(),
+        stderr: "oops\n".to_string(),
+        exit_code,
+        assertions: Vec::new(),
+        timing: Timing::default(),
+    };
+
+    let passed = res(0);
+    assert_eq!(
+        reporter.format_event(&ProcessingEvent::CommandFinished(
+            &set,
+            &passed,
+            Duration::from_millis(12)
+        )),
+        "  PASS test 1 > echo Hello (12ms)\n"
+    );
+
+    let failed = res(2);
+    assert_eq!(
+        reporter.format_event(&ProcessingEvent::CommandFinished(
+            &set,
+            &failed,
+            Duration::from_millis(1500)
+        )),
+        "  FAIL test 1 > echo Hello (1.50s)\n       Exited with code 2, expected 0\n       --- stdout ---\n       Hello\n       --- stderr ---\n       oops\n"
+    );
+}
+
+#[test]
+fn t_console_color() {
+    let reporter = ConsoleReporter::new(true);
+    let set = test_set("echo Hello");
+
+    assert_eq!(
+        reporter.format_event(&ProcessingEvent::SetStarted(&set)),
+        "\x1b[36mRUN \x1b[0m test 1\n"
+    );
+}
+
+#[test]
+fn t_console_module_prefix() {
+    let reporter = ConsoleReporter::new(false).with_module_labels(ModuleLabels::Prefix);
+    let set = test_set("echo Hello");
+
+    assert_eq!(
+        reporter.format_run_event(&RunEvent::Processing(
+            "a.fcheck.toml",
+            &ProcessingEvent::SetStarted(&set)
+        )),
+        "[a.fcheck.toml] RUN  test 1\n"
+    );
+    assert_eq!(
+        reporter.format_run_event(&RunEvent::ModuleStarted("a.fcheck.toml")),
+        ""
+    );
+
+    let reporter = ConsoleReporter::new(false).with_module_labels(ModuleLabels::Headers);
+    assert_eq!(
+        reporter.format_run_event(&RunEvent::ModuleStarted("a.fcheck.toml")),
+        "\n==> a.fcheck.toml\n"
+    );
+}
+
+#[test]
+fn t_console_slowest() {
+    use crate::model::{CommandFamily, ProcessingModule, TeardownPolicy};
+    use crate::processor::run_all;
+
+    // Each command takes as many milliseconds as it says
+    let runner = |command: &ExecutableCommand| CommandResult::StandardResult {
+        command: command.clone(),
+        stdout: String::new(),
+        stderr: String::new(),
+        exit_code: 0,
+        assertions: Vec::new(),
+        timing: Timing {
+            duration: Duration::from_millis(command.cmd.parse().unwrap()),
+            ..Default::default()
+        },
+    };
+    let module = |setup: &str, tests: &[&str]| ProcessingModule {
+        shell: Shell::default(),
+        setup: test_set(setup),
+        services: Vec::new(),
+        tests: CommandFamily {
+            sets: tests.iter().map(|cmd| test_set(cmd)).collect(),
+            processing_kind: ProcessingKind::Serial,
+            fail_fast: false,
+        },
+        teardown: test_set("1"),
+        teardown_policy: TeardownPolicy::Always,
+    };
+    let modules = vec![
+        ("a.fcheck.toml".to_string(), module("20", &["300", "5"])),
+        ("b.fcheck.toml".to_string(), module("1500", &["40"])),
+    ];
+    let run = run_all(&modules, 1, &runner, &|_| ());
+
+    let reporter = ConsoleReporter::new(false);
+    assert_eq!(
+        reporter.format_slowest(&run, 3),
+        "\nSlowest Commands\n     1.50s  [b.fcheck.toml] test 1 > 1500\n     300ms  [a.fcheck.toml] test 1 > 300\n      40ms  [b.fcheck.toml] test 1 > 40\n"
+    );
+    assert_eq!(reporter.format_slowest(&run, 0), "");
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+use std::io;
+
+use crate::parser::ParseError;
+
+// Exit codes of fcheck, so CI can tell a broken config from a failing test
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_TEST_FAILURE: i32 = 1;
+pub const EXIT_CONFIG_ERROR: i32 = 2;
+pub const EXIT_INTERNAL_ERROR: i32 = 3;
+// Like a shell, 128 + SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Debug)]
+pub enum FcheckError {
+    ConfigNotFound(String),
+    // A config file, or a directory without config files
+    UnsupportedFormat(String),
+    Parse {
+        file: String,
+        // Contents of the file, to show where the error is
+        source: String,
+        error: ParseError,
+    },
+    // A command line argument that can't be used
+    InvalidArgument(String),
+    Io {
+        context: String,
+        error: io::Error,
+    },
+    ReportWrite {
+        path: String,
+        error: io::Error,
+    },
+}
+
+impl FcheckError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FcheckError::ConfigNotFound(_)
+            | FcheckError::UnsupportedFormat(_)
+            | FcheckError::Parse { .. }
+            | FcheckError::InvalidArgument(_) => EXIT_CONFIG_ERROR,
+            FcheckError::Io { .. } | FcheckError::ReportWrite { .. } => EXIT_INTERNAL_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for FcheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FcheckError::ConfigNotFound(path) => write!(f, "Config file not found: {}", path),
+            FcheckError::UnsupportedFormat(message) => write!(f, "{}", message),
+            FcheckError::Parse {
+                file,
+                source,
+                error,
+            } => {
+                write!(f, "Failed to process config file {}. {}", file, error)?;
+                if let Some((line, col)) = error.line_col() {
+                    write!(f, "\n{}", snippet(file, source, line, col))?;
+                }
+                Ok(())
+            }
+            FcheckError::InvalidArgument(message) => write!(f, "{}", message),
+            FcheckError::Io { context, error } => write!(f, "{}: {}", context, error),
+            FcheckError::ReportWrite { path, error } => {
+                write!(f, "Unable to write report file {}: {}", path, error)
+            }
+        }
+    }
+}
+
+// The line of the error with a caret under the column, both counted from 0
+//   --> config.toml:4:9
+//    |
+//  4 |         garbage
+//    |         ^
+fn snippet(file: &str, source: &str, line: usize, col: usize) -> String {
+    let text = source.lines().nth(line).unwrap_or("");
+    let number = (line + 1).to_string();
+    let margin = " ".repeat(number.len());
+    // Tabs are kept, so the caret lines up with the text
+    let indent: String = text
+        .chars()
+        .take(col)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}^",
+        margin,
+        file,
+        line + 1,
+        col + 1,
+        margin,
+        number,
+        text,
+        margin,
+        indent
+    )
+}
+
+#[test]
+fn t_snippet() {
+    let source = "version = \"3\"\n\n\tgarbage\n";
+    assert_eq!(
+        snippet("config.toml", source, 2, 8),
+        " --> config.toml:3:9\n  |\n3 | \tgarbage\n  | \t       ^"
+    );
+}
+
+#[test]
+fn t_exit_codes() {
+    let io_error = || io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+
+    assert_eq!(
+        FcheckError::ConfigNotFound("a.toml".to_string()).exit_code(),
+        EXIT_CONFIG_ERROR
+    );
+    assert_eq!(
+        FcheckError::InvalidArgument("--var".to_string()).exit_code(),
+        EXIT_CONFIG_ERROR
+    );
+    assert_eq!(
+        FcheckError::ReportWrite {
+            path: "out.json".to_string(),
+            error: io_error()
+        }
+        .exit_code(),
+        EXIT_INTERNAL_ERROR
+    );
+    assert_eq!(
+        FcheckError::Io {
+            context: "Failed to read a.toml".to_string(),
+            error: io_error()
+        }
+        .to_string(),
+        "Failed to read a.toml: denied"
+    );
+}
+
+#[test]
+fn t_parse_error_display() {
+    use crate::model::Variables;
+    use crate::parser::{prepare_file, FileType};
+
+    let source = "version = \"3\"\n\n    garbage\n";
+    let error = prepare_file(FileType::Toml, source.to_string(), &Variables::new())
+        .expect_err("Should have failed");
+    let error = FcheckError::Parse {
+        file: "config.toml".to_string(),
+        source: source.to_string(),
+        error,
+    };
+
+    assert_eq!(
+        error.to_string(),
+        "Failed to process config file config.toml. expected an equals, found a newline at line 3\n --> config.toml:3:12\n  |\n3 |     garbage\n  |            ^"
+    );
+    assert_eq!(error.exit_code(), EXIT_CONFIG_ERROR);
+}
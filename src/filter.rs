@@ -0,0 +1,182 @@
+use regex::Regex;
+
+use crate::model::{CommandFamily, CommandSet};
+
+// Selects the tests to run from the command line. Tests that are not
+// selected are still reported, as skipped with the reason.
+#[derive(Debug, Default)]
+pub struct TestFilter {
+    // Only run tests with a name matching one of these
+    pub names: Vec<NamePattern>,
+    // Don't run tests with a name matching one of these
+    pub skip: Vec<NamePattern>,
+    // Only run tests with one of these tags
+    pub tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+}
+
+// A glob like `api-*`, or a regex when it starts with `re:`
+#[derive(Debug)]
+pub struct NamePattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl NamePattern {
+    pub fn new(pattern: &str) -> Result<NamePattern, String> {
+        let regex = match pattern.strip_prefix("re:") {
+            Some(re) => Regex::new(re),
+            None => Regex::new(&glob_to_regex(pattern)),
+        }
+        .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?;
+        Ok(NamePattern {
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+}
+
+// `*` is any text, `?` any character and `[...]` a class, the glob matches the whole name
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut in_class = false;
+    for c in glob.chars() {
+        match c {
+            '*' if !in_class => regex.push_str(".*"),
+            '?' if !in_class => regex.push('.'),
+            '[' if !in_class => {
+                in_class = true;
+                regex.push('[');
+            }
+            ']' if in_class => {
+                in_class = false;
+                regex.push(']');
+            }
+            '!' if in_class && regex.ends_with('[') => regex.push('^'),
+            '\\' | '^' if in_class => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            _ if in_class => regex.push(c),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+impl TestFilter {
+    // Why the set is not selected, None when it runs
+    pub fn skip_reason(&self, set: &CommandSet) -> Option<String> {
+        let name = set.name.as_deref().unwrap_or("");
+        if let Some(p) = self.skip.iter().find(|p| p.matches(name)) {
+            return Some(format!("Skipped by --skip '{}'", p.pattern));
+        }
+        if !self.names.is_empty() && !self.names.iter().any(|p| p.matches(name)) {
+            return Some("Doesn't match --filter".to_string());
+        }
+        if let Some(tag) = self.exclude_tags.iter().find(|t| set.tags.contains(t)) {
+            return Some(format!("Excluded by tag '{}'", tag));
+        }
+        if !self.tags.is_empty() && !self.tags.iter().any(|t| set.tags.contains(t)) {
+            return Some(format!("Not tagged {}", quote_list(&self.tags)));
+        }
+        None
+    }
+}
+
+fn quote_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|i| format!("'{}'", i))
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+// Marks the tests the filter doesn't select as skipped. Disabled tests stay
+// skipped with their own reason.
+pub fn filter_tests(family: &mut CommandFamily, filter: &TestFilter) {
+    for set in family.sets.iter_mut().filter(|s| s.skip.is_none()) {
+        set.skip = filter.skip_reason(set);
+    }
+}
+
+#[cfg(test)]
+use crate::model::ProcessingKind;
+
+#[cfg(test)]
+fn test_set(name: &str, tags: &[&str]) -> CommandSet {
+    CommandSet {
+        name: Some(name.to_string()),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn t_name_pattern() {
+    let matches = |pattern: &str, name: &str| NamePattern::new(pattern).unwrap().matches(name);
+
+    assert!(matches("api-*", "api-get"));
+    assert!(!matches("api-*", "the api-get"));
+    assert!(matches("test?", "test1"));
+    assert!(!matches("test?", "test10"));
+    assert!(matches("test[12]", "test2"));
+    assert!(!matches("test[!12]", "test2"));
+    assert!(matches("a.b (1)", "a.b (1)"));
+    assert!(!matches("a.b", "axb"));
+    assert!(matches("re:^api-(get|post)$", "api-post"));
+    assert!(matches("re:post", "api-post"));
+    assert!(NamePattern::new("re:(").is_err());
+}
+
+#[test]
+fn t_filter_tests() {
+    let mut family = CommandFamily {
+        sets: vec![
+            test_set("api-get", &["smoke", "http"]),
+            test_set("api-post", &["http"]),
+            test_set("cli", &["smoke"]),
+            test_set("slow", &["smoke"]),
+        ],
+        processing_kind: ProcessingKind::Serial,
+        fail_fast: false,
+    };
+    family.sets[3].skip = Some("Disabled".to_string());
+
+    let filter = TestFilter {
+        names: vec![NamePattern::new("api-*").unwrap()],
+        tags: vec!["smoke".to_string()],
+        ..Default::default()
+    };
+    filter_tests(&mut family, &filter);
+    let reasons: Vec<_> = family.sets.iter().map(|s| s.skip.clone()).collect();
+    assert_eq!(
+        reasons,
+        vec![
+            None,
+            Some("Not tagged 'smoke'".to_string()),
+            Some("Doesn't match --filter".to_string()),
+            Some("Disabled".to_string()),
+        ]
+    );
+
+    let filter = TestFilter {
+        skip: vec![NamePattern::new("*-get").unwrap()],
+        exclude_tags: vec!["http".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        filter.skip_reason(&test_set("api-get", &[])),
+        Some("Skipped by --skip '*-get'".to_string())
+    );
+    assert_eq!(
+        filter.skip_reason(&test_set("api-post", &["http"])),
+        Some("Excluded by tag 'http'".to_string())
+    );
+    assert_eq!(filter.skip_reason(&test_set("cli", &["smoke"])), None);
+}
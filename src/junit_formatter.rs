@@ -0,0 +1,667 @@
+use std::time::{Duration, SystemTime};
+
+use crate::model::{
+    CommandResult, CommandSet, CommandSetResult, ExecutableCommand, ProcessingModuleResult,
+    RunResult,
+};
+use crate::output_formatter::format_timestamp;
+
+// JUnit XML report
+//   * Each test (CommandSetResult) is a <testcase>
+//   * Setup and teardown results are <properties> of the <testsuite>
+//   * Timeouts, OS/runtime errors and irregular exit codes are <error>s
+//   * A non-zero exit code is a <failure>
+//   * So is a test or command that passed but took longer than its max_duration
+//   * Services are <properties> too, a service that stopped during a test is an <error>
+//   * Tests that never ran, because setup or a service failed, are <error>s
+//   * Disabled tests and tests the command line filtered out are <skipped>
+//   * Commands not run because a budget was used up are <error>s
+//   * Failures a test or command allows pass, the JSON report keeps them as warnings
+
+enum CaseOutcome {
+    Passed,
+    Failure(String, String),
+    Error(String, String),
+    Skipped(String),
+}
+
+// Counts of the <testsuite>, totalled in <testsuites>
+#[derive(Default)]
+struct SuiteCounts {
+    tests: usize,
+    failures: usize,
+    errors: usize,
+    skipped: usize,
+}
+
+pub fn format_module_junit(module: &ProcessingModuleResult) -> String {
+    let (suite, counts) = format_testsuite("fcheck", module);
+    format_testsuites(&[suite], &counts, module.timing.duration)
+}
+
+// Each config file of the run is a <testsuite>, named after the file
+pub fn format_run_junit(run: &RunResult) -> String {
+    let mut total = SuiteCounts::default();
+    let suites: Vec<String> = run
+        .modules
+        .iter()
+        .map(|m| {
+            let (suite, counts) = format_testsuite(&m.file, &m.result);
+            total.tests += counts.tests;
+            total.failures += counts.failures;
+            total.errors += counts.errors;
+            total.skipped += counts.skipped;
+            suite
+        })
+        .collect();
+    format_testsuites(&suites, &total, run.timing.duration)
+}
+
+fn format_testsuites(suites: &[String], counts: &SuiteCounts, duration: Duration) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"fcheck\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{}\">\n",
+        counts.tests, counts.failures, counts.errors, counts.skipped, seconds(duration)
+    ));
+    for suite in suites {
+        xml.push_str(suite);
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn format_testsuite(name: &str, module: &ProcessingModuleResult) -> (String, SuiteCounts) {
+    let mut properties = Vec::new();
+    properties.push((
+        "setup".to_string(),
+        result_to_string(module.setup.success()),
+    ));
+    properties.extend(failed_command_properties("setup", &module.setup));
+    for service in module.services.iter() {
+        let name = format!("service.{}", service.service.name);
+        properties.push((name.clone(), result_to_string(service.success())));
+        properties.extend(
+            service
+                .error
+                .clone()
+                .map(|error| (format!("{}.failure", name), error)),
+        );
+    }
+    match &module.teardown {
+        Some(teardown) => {
+            properties.push(("teardown".to_string(), result_to_string(teardown.success())));
+            properties.extend(failed_command_properties("teardown", teardown));
+        }
+        None => {
+            properties.push(("teardown".to_string(), "skipped".to_string()));
+            properties.extend(
+                module
+                    .teardown_skipped_reason()
+                    .map(|reason| ("teardown.skipped".to_string(), reason)),
+            );
+        }
+    }
+
+    let cases: Vec<(&CommandSet, CaseOutcome, Option<&CommandSetResult>)> = match &module.tests {
+        Some(tests) => tests
+            .sets
+            .iter()
+            .map(|set| (&set.set, set_outcome(set), Some(set)))
+            .collect(),
+        // The tests could not run, which fails them unless they were disabled
+        None => {
+            let (kind, message) = environment_failure(module);
+            module
+                .module
+                .tests
+                .sets
+                .iter()
+                .map(|set| match &set.skip {
+                    Some(reason) => (set, CaseOutcome::Skipped(reason.clone()), None),
+                    None => (set, CaseOutcome::Error(kind.clone(), message.clone()), None),
+                })
+                .collect()
+        }
+    };
+
+    let count = |f: fn(&CaseOutcome) -> bool| cases.iter().filter(|(_, o, _)| f(o)).count();
+    let counts = SuiteCounts {
+        tests: cases.len(),
+        failures: count(|o| matches!(o, CaseOutcome::Failure(_, _))),
+        errors: count(|o| matches!(o, CaseOutcome::Error(_, _))),
+        skipped: count(|o| matches!(o, CaseOutcome::Skipped(_))),
+    };
+
+    let mut xml = format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" timestamp=\"{}\" time=\"{}\">\n",
+        escape(name),
+        counts.tests,
+        counts.failures,
+        counts.errors,
+        counts.skipped,
+        timestamp(module.timing.started),
+        seconds(module.timing.duration)
+    );
+    xml.push_str("    <properties>\n");
+    for (name, value) in properties {
+        xml.push_str(&format!(
+            "      <property name=\"{}\" value=\"{}\"/>\n",
+            escape(&name),
+            escape(&value)
+        ));
+    }
+    xml.push_str("    </properties>\n");
+    for (i, (set, outcome, result)) in cases.into_iter().enumerate() {
+        xml.push_str(&format_testcase(i, set, outcome, result));
+    }
+    xml.push_str("  </testsuite>\n");
+    (xml, counts)
+}
+
+fn result_to_string(b: bool) -> String {
+    if b {
+        "success".to_string()
+    } else {
+        "failure".to_string()
+    }
+}
+
+fn failed_command_properties(prefix: &str, set: &CommandSetResult) -> Vec<(String, String)> {
+    set.all_results()
+        .filter(|res| !res.success() && !res.allowed_failure())
+        .map(|res| {
+            let (_, message) = command_failure(res);
+            (format!("{}.failure", prefix), message)
+        })
+        .collect()
+}
+
+// Why the tests of a module didn't run, the setup or a service failed
+fn environment_failure(module: &ProcessingModuleResult) -> (String, String) {
+    let reason = module.tests_skipped_reason().unwrap_or_default();
+    if !module.setup.success() {
+        let detail = module
+            .setup
+            .all_results()
+            .find(|res| !res.success() && !res.allowed_failure())
+            .map(|res| command_failure(res.last_attempt()).1);
+        return (
+            "SetupFailed".to_string(),
+            match detail {
+                Some(detail) => format!("{}. {}", reason, detail),
+                None => reason,
+            },
+        );
+    }
+    let detail = module
+        .services
+        .iter()
+        .find(|s| !s.success())
+        .and_then(|s| s.error.clone());
+    (
+        "ServiceFailed".to_string(),
+        match detail {
+            Some(detail) => format!("{}. {}", reason, detail),
+            None => reason,
+        },
+    )
+}
+
+fn set_outcome(set: &CommandSetResult) -> CaseOutcome {
+    if let Some(reason) = &set.set.skip {
+        return CaseOutcome::Skipped(reason.clone());
+    }
+    // Like in a dry run, where no command of the test runs
+    if let Some(CommandResult::NotRun {
+        reason,
+        aborted: false,
+        ..
+    }) = set.all_results().next()
+    {
+        if set
+            .all_results()
+            .all(|res| matches!(res, CommandResult::NotRun { aborted: false, .. }))
+        {
+            return CaseOutcome::Skipped(reason.clone());
+        }
+    }
+    if let Some(reason) = set.not_run() {
+        return CaseOutcome::Error("NotRun".to_string(), reason);
+    }
+    if set.allowed_failure() {
+        return CaseOutcome::Passed;
+    }
+    match set
+        .all_results()
+        .find(|res| !res.success() && !res.allowed_failure())
+        .map(|res| res.last_attempt())
+    {
+        None => match set.over_max_duration() {
+            Some(reason) => CaseOutcome::Failure("Slow".to_string(), format!("Test {}", reason)),
+            None => CaseOutcome::Passed,
+        },
+        Some(res @ CommandResult::StandardResult { .. }) => {
+            let (kind, message) = command_failure(res);
+            CaseOutcome::Failure(kind, message)
+        }
+        Some(res) => {
+            let (kind, message) = command_failure(res);
+            CaseOutcome::Error(kind, message)
+        }
+    }
+}
+
+fn command_label(command: &ExecutableCommand) -> String {
+    match &command.name {
+        Some(name) => format!("'{}'", name),
+        None => format!("'{}'", command.cmd),
+    }
+}
+
+// Returns the error type and message of a command that did not succeed
+fn command_failure(res: &CommandResult) -> (String, String) {
+    match res {
+        CommandResult::OsError { command, error, .. } => (
+            "OsError".to_string(),
+            format!(
+                "Command {} could not be started: {}",
+                command_label(command),
+                error
+            ),
+        ),
+        CommandResult::RuntimeError { command, error, .. } => (
+            "RuntimeError".to_string(),
+            format!(
+                "Command {} failed to run: {}",
+                command_label(command),
+                error
+            ),
+        ),
+        CommandResult::Timeout {
+            command,
+            signal,
+            reason: Some(reason),
+            ..
+        } => (
+            "Timeout".to_string(),
+            format!(
+                "Command {} stopped after {}ms with {}: {}",
+                command_label(command),
+                command.timeout.unwrap_or(0),
+                signal,
+                reason
+            ),
+        ),
+        CommandResult::Timeout {
+            command, signal, ..
+        } => (
+            "Timeout".to_string(),
+            format!(
+                "Command {} timed out after {}ms, stopped with {}",
+                command_label(command),
+                command.timeout.unwrap_or(0),
+                signal
+            ),
+        ),
+        CommandResult::IrregularExitCode {
+            command, exit_code, ..
+        } => (
+            "IrregularExitCode".to_string(),
+            format!(
+                "Command {} exited with {}",
+                command_label(command),
+                exit_code
+            ),
+        ),
+        CommandResult::StandardResult { command, .. } if res.slow() => (
+            "Slow".to_string(),
+            format!(
+                "Command {} {}",
+                command_label(command),
+                res.slow_reason().unwrap_or_default()
+            ),
+        ),
+        CommandResult::StandardResult {
+            command,
+            exit_code,
+            assertions,
+            ..
+        } => match assertions.iter().find(|a| !a.success) {
+            Some(assertion) if command.expect.exit_codes.contains(exit_code) => (
+                "Assertion".to_string(),
+                format!("Command {} {}", command_label(command), assertion.message()),
+            ),
+            _ => (
+                "ExitCode".to_string(),
+                format!(
+                    "Command {} exited with code {}, expected {:?}",
+                    command_label(command),
+                    exit_code,
+                    command.expect.exit_codes
+                ),
+            ),
+        },
+        CommandResult::Retried { attempts, .. } => {
+            let (kind, message) = command_failure(res.last_attempt());
+            (
+                kind,
+                format!("{}, after {} attempts", message, attempts.len()),
+            )
+        }
+        CommandResult::NotRun {
+            command, reason, ..
+        } => (
+            "NotRun".to_string(),
+            format!("Command {} was not run: {}", command_label(command), reason),
+        ),
+        CommandResult::ServiceCrashed {
+            command,
+            service,
+            exit_status,
+            ..
+        } => (
+            "ServiceCrashed".to_string(),
+            format!(
+                "Command {} failed, service '{}' {}",
+                command_label(command),
+                service,
+                exit_status
+            ),
+        ),
+    }
+}
+
+fn format_testcase(
+    index: usize,
+    set: &CommandSet,
+    outcome: CaseOutcome,
+    result: Option<&CommandSetResult>,
+) -> String {
+    let name = set
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("Test #{}", index + 1));
+
+    let duration = result.map(|r| r.timing.duration).unwrap_or_default();
+    let mut xml = format!(
+        "    <testcase name=\"{}\" classname=\"fcheck\" time=\"{}\">\n",
+        escape(&name),
+        seconds(duration)
+    );
+    match outcome {
+        CaseOutcome::Passed => (),
+        CaseOutcome::Failure(kind, message) => xml.push_str(&format!(
+            "      <failure message=\"{}\" type=\"{}\"/>\n",
+            escape(&message),
+            escape(&kind)
+        )),
+        CaseOutcome::Error(kind, message) => xml.push_str(&format!(
+            "      <error message=\"{}\" type=\"{}\"/>\n",
+            escape(&message),
+            escape(&kind)
+        )),
+        CaseOutcome::Skipped(message) => xml.push_str(&format!(
+            "      <skipped message=\"{}\"/>\n",
+            escape(&message)
+        )),
+    }
+
+    if let Some(result) = result {
+        let mut out = String::new();
+        let mut err = String::new();
+        for res in result.all_results() {
+            let cmd = &res.command().cmd;
+            out.push_str(&format!("$ {}\n{}", cmd, res.stdout()));
+            if !res.stderr().is_empty() {
+                err.push_str(&format!("$ {}\n{}", cmd, res.stderr()));
+            }
+        }
+        xml.push_str(&format!(
+            "      <system-out>{}</system-out>\n",
+            escape(&out)
+        ));
+        xml.push_str(&format!(
+            "      <system-err>{}</system-err>\n",
+            escape(&err)
+        ));
+    }
+
+    xml.push_str("    </testcase>\n");
+    xml
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+// The JUnit schema has timestamps without a time zone, they are in UTC
+fn timestamp(time: SystemTime) -> String {
+    format_timestamp(time).chars().take(19).collect()
+}
+
+// Escapes XML special characters and drops characters XML 1.0 can't contain
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if (c as u32) < 0x20 => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+use crate::model::{
+    CommandFamily, CommandFamilyResult, CommandSetType, ProcessingKind, Shell, Timing, Variables,
+};
+
+#[cfg(test)]
+fn command(cmd: &str) -> ExecutableCommand {
+    ExecutableCommand {
+        name: Option::None,
+        description: Option::None,
+        timeout: Some(100),
+        shell: Shell::default(),
+        cmd: cmd.to_string(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+fn set(name: &str, set_type: CommandSetType, commands: Vec<ExecutableCommand>) -> CommandSet {
+    CommandSet {
+        name: Some(name.to_string()),
+        set_type,
+        commands,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn t_junit_module() {
+    let setup = set("Setup", CommandSetType::Setup, vec![command("echo setup")]);
+    let passing = set("pass & go", CommandSetType::Test, vec![command("echo ok")]);
+    let failing = set("fail", CommandSetType::Test, vec![command("exit 1")]);
+    let timeout = set("slow", CommandSetType::Test, vec![command("sleep 1")]);
+    let teardown = set("Teardown", CommandSetType::Teardown, vec![]);
+    let family = CommandFamily {
+        sets: vec![passing.clone(), failing.clone(), timeout.clone()],
+        processing_kind: ProcessingKind::Serial,
+        fail_fast: false,
+    };
+
+    let res = ProcessingModuleResult {
+        module: crate::model::ProcessingModule {
+            shell: Shell::default(),
+            setup: setup.clone(),
+            services: Vec::new(),
+            tests: family.clone(),
+            teardown: teardown.clone(),
+            teardown_policy: crate::model::TeardownPolicy::Always,
+        },
+        setup: CommandSetResult {
+            results: vec![CommandResult::StandardResult {
+                command: setup.commands[0].clone(),
+                stdout: "setup\n".to_string(),
+                stderr: String::new(),
+                exit_code: 0,
+                assertions: Vec::new(),
+                timing: Timing::default(),
+            }],
+            set: setup,
+            setup: Vec::new(),
+            teardown: Vec::new(),
+            captures: Variables::new(),
+            timing: Timing::default(),
+        },
+        services: Vec::new(),
+        tests: Some(CommandFamilyResult {
+            sets: vec![
+                CommandSetResult {
+                    results: vec![CommandResult::StandardResult {
+                        command: passing.commands[0].clone(),
+                        stdout: "<ok>\n".to_string(),
+                        stderr: String::new(),
+                        exit_code: 0,
+                        assertions: Vec::new(),
+                        timing: Timing::default(),
+                    }],
+                    set: passing,
+                    setup: Vec::new(),
+                    teardown: Vec::new(),
+                    captures: Variables::new(),
+                    timing: Timing {
+                        started: std::time::UNIX_EPOCH,
+                        duration: Duration::from_millis(1234),
+                    },
+                },
+                CommandSetResult {
+                    results: vec![CommandResult::StandardResult {
+                        command: failing.commands[0].clone(),
+                        stdout: String::new(),
+                        stderr: "bad\n".to_string(),
+                        exit_code: 1,
+                        assertions: Vec::new(),
+                        timing: Timing::default(),
+                    }],
+                    set: failing,
+                    setup: Vec::new(),
+                    teardown: Vec::new(),
+                    captures: Variables::new(),
+                    timing: Timing::default(),
+                },
+                CommandSetResult {
+                    results: vec![CommandResult::Timeout {
+                        command: timeout.commands[0].clone(),
+                        stdout: String::new(),
+                        stderr: String::new(),
+                        signal: "SIGTERM".to_string(),
+                        reason: None,
+                        timing: Timing::default(),
+                    }],
+                    set: timeout,
+                    setup: Vec::new(),
+                    teardown: Vec::new(),
+                    captures: Variables::new(),
+                    timing: Timing::default(),
+                },
+            ],
+            family,
+            timing: Timing::default(),
+        }),
+        teardown: Some(CommandSetResult {
+            set: teardown,
+            results: vec![],
+            setup: Vec::new(),
+            teardown: Vec::new(),
+            captures: Variables::new(),
+            timing: Timing::default(),
+        }),
+        timing: Timing::default(),
+    };
+
+    let xml = format_module_junit(&res);
+
+    assert!(xml.contains(
+        "<testsuite name=\"fcheck\" tests=\"3\" failures=\"1\" errors=\"1\" skipped=\"0\" timestamp=\"1970-01-01T00:00:00\" time=\"0.000\">"
+    ));
+    assert!(xml.contains("<property name=\"setup\" value=\"success\"/>"));
+    assert!(xml.contains("<property name=\"teardown\" value=\"success\"/>"));
+    assert!(xml.contains("<testcase name=\"pass &amp; go\" classname=\"fcheck\" time=\"1.234\">"));
+    assert!(xml.contains("<system-out>$ echo ok\n&lt;ok&gt;\n</system-out>"));
+    assert!(xml.contains(
+        "<failure message=\"Command &apos;exit 1&apos; exited with code 1, expected [0]\" type=\"ExitCode\"/>"
+    ));
+    assert!(xml.contains("<system-err>$ exit 1\nbad\n</system-err>"));
+    assert!(xml.contains(
+        "<error message=\"Command &apos;sleep 1&apos; timed out after 100ms, stopped with SIGTERM\" type=\"Timeout\"/>"
+    ));
+}
+
+#[test]
+fn t_junit_setup_failed() {
+    let setup = set("Setup", CommandSetType::Setup, vec![command("exit 2")]);
+    let mut disabled = set("disabled", CommandSetType::Test, vec![command("true")]);
+    disabled.skip = Some("Disabled".to_string());
+    let res = ProcessingModuleResult {
+        module: crate::model::ProcessingModule {
+            shell: Shell::default(),
+            setup: setup.clone(),
+            services: Vec::new(),
+            tests: CommandFamily {
+                sets: vec![
+                    set("a", CommandSetType::Test, vec![command("true")]),
+                    set("b", CommandSetType::Test, vec![command("true")]),
+                    disabled,
+                ],
+                processing_kind: ProcessingKind::Serial,
+                fail_fast: false,
+            },
+            teardown: set("Teardown", CommandSetType::Teardown, vec![]),
+            teardown_policy: crate::model::TeardownPolicy::Never,
+        },
+        setup: CommandSetResult {
+            results: vec![CommandResult::StandardResult {
+                command: setup.commands[0].clone(),
+                stdout: String::new(),
+                stderr: String::new(),
+                exit_code: 2,
+                assertions: Vec::new(),
+                timing: Timing::default(),
+            }],
+            set: setup,
+            setup: Vec::new(),
+            teardown: Vec::new(),
+            captures: Variables::new(),
+            timing: Timing::default(),
+        },
+        services: Vec::new(),
+        tests: None,
+        teardown: None,
+        timing: Timing::default(),
+    };
+
+    let xml = format_module_junit(&res);
+
+    // A broken environment is an error of the tests, not a skip
+    assert!(xml.contains("tests=\"3\" failures=\"0\" errors=\"2\" skipped=\"1\""));
+    assert!(xml.contains(
+        "<error message=\"Setup failed. Command &apos;exit 2&apos; exited with code 2, expected [0]\" type=\"SetupFailed\"/>"
+    ));
+    assert!(xml.contains("<skipped message=\"Disabled\"/>"));
+}
+
+#[test]
+fn t_junit_escape() {
+    assert_eq!(
+        escape("a<b>&\"c'\u{1b}[0m\n"),
+        "a&lt;b&gt;&amp;&quot;c&apos;[0m\n"
+    );
+}
@@ -0,0 +1,23 @@
+// fcheck as a library, to run config files from other Rust programs and tests.
+//   * `parser::prepare_file` maps a config into a `model::ProcessingModule`
+//   * `config::load_modules` loads config files with the settings of the command line
+//   * `processor::run_processingmodule` runs it, with a `runner::CommandRunner` that runs each command
+//   * `output_formatter`, `junit_formatter` and `console_reporter` report the result
+//   * `plan_formatter` shows what a module would run, without running it
+// The fcheck binary is a command line wrapper around this.
+
+pub mod config;
+pub mod console_reporter;
+pub mod error;
+pub mod filter;
+pub mod junit_formatter;
+pub mod model;
+pub mod output_formatter;
+pub mod parser;
+pub mod plan_formatter;
+pub mod processor;
+pub mod runner;
+
+mod assertions;
+mod services;
+mod variables;
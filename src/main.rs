@@ -1,32 +1,36 @@
 // #![feature(result_map_or_else)]
 
-use clap::{App, Arg};
-use std::ffi::OsStr;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::fs;
+use std::io::IsTerminal;
+use std::panic;
 use std::path::Path;
+use std::time::Duration;
 
-mod model;
-mod output_formatter;
-mod parser;
-mod processor;
-
-use output_formatter::format_module;
-use parser::{file_extension_to_filetype, prepare_file};
-use processor::run;
+use fcheck::config::{self, LoadOptions};
+use fcheck::console_reporter::{ConsoleReporter, ModuleLabels};
+use fcheck::error::{
+    FcheckError, EXIT_INTERNAL_ERROR, EXIT_INTERRUPTED, EXIT_SUCCESS, EXIT_TEST_FAILURE,
+};
+use fcheck::filter::{NamePattern, TestFilter};
+use fcheck::junit_formatter::{format_module_junit, format_run_junit};
+use fcheck::model::{ProcessingModule, Variables};
+use fcheck::output_formatter::{format_module, format_run};
+use fcheck::parser::default_max_parallel;
+use fcheck::plan_formatter::{format_plan_json, format_plan_text};
+use fcheck::processor::{
+    interrupted, run_all, stop_process_groups, RunEvent, DEFAULT_GRACE_PERIOD_MS,
+};
+use fcheck::runner::{
+    BudgetRunner, CommandRunner, DryRunRunner, LocalRunner, RecordingRunner, ReplayRunner,
+};
 
 fn main() {
     let matches = App::new("fcheck")
         .version("0.3.0")
         .about("A language agnostic orchestration tool for integration and system testing.")
-        .arg(
-            Arg::with_name("config-file")
-                .short("c")
-                .long("config-file")
-                // .value_name("FILE")
-                .help("Configuration file containing tests to be run")
-                .takes_value(true)
-                .required(true),
-        )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&config_args())
         .arg(
             Arg::with_name("report-file")
                 .short("r")
@@ -35,6 +39,91 @@ fn main() {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .help("Format of the output report")
+                .takes_value(true)
+                .possible_values(&["json", "junit"])
+                .default_value("json")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("parallel-modules")
+                .long("parallel-modules")
+                .help("Run the config files in parallel")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-parallel-modules")
+                .long("max-parallel-modules")
+                .help("Maximum number of config files to run at the same time. Implies --parallel-modules")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no-color")
+                .long("no-color")
+                .help("Don't color the console output")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("slowest")
+                .long("slowest")
+                .value_name("N")
+                .help("Number of the slowest commands listed at the end of the run, 0 to not list them")
+                .takes_value(true)
+                .default_value("5")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Reports the commands that would run, without running them")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Records the result of every command to a file, to replay the run later")
+                .takes_value(true)
+                .conflicts_with("dry-run")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .help("Uses the results of a file written with --record instead of running the commands")
+                .takes_value(true)
+                .conflicts_with_all(&["dry-run", "record"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("budget")
+                .long("budget")
+                .value_name("MS")
+                .help("Time the whole run may take. Commands left when it is used up are not run, which fails them")
+                .takes_value(true)
+                .required(false),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Prints what the config files would run, without running anything")
+                .args(&config_args())
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .help("Format of the plan")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .required(false),
+                ),
+        )
         // .arg(Arg::with_name("v")
         //     .short("v")
         //     .multiple(true)
@@ -47,10 +136,118 @@ fn main() {
     //   .option('-v, --verbose-errors', 'Verbose error logging')
     //   .parse(process.argv);
 
-    let config_file = matches.value_of("config-file").unwrap_or("./config.toml");
+    // Commands and services run in their own process groups, so a Ctrl-C
+    // doesn't reach them
+    let handler = ctrlc::set_handler(|| {
+        eprintln!("Interrupted, stopping the running commands");
+        stop_process_groups(Duration::from_millis(DEFAULT_GRACE_PERIOD_MS));
+        std::process::exit(EXIT_INTERRUPTED)
+    });
+    if let Err(err) = handler {
+        eprintln!("Can't stop the running commands on Ctrl-C: {}", err);
+    }
+
+    // A panic is a bug in fcheck, not a failed test
+    let code = match panic::catch_unwind(|| run_cli(&matches)) {
+        Ok(Ok(true)) => EXIT_SUCCESS,
+        Ok(Ok(false)) => EXIT_TEST_FAILURE,
+        Ok(Err(err)) => {
+            eprintln!("{}", err);
+            err.exit_code()
+        }
+        Err(_) => EXIT_INTERNAL_ERROR,
+    };
+    // The run can end before the handler, with the commands it stopped
+    std::process::exit(if interrupted() {
+        EXIT_INTERRUPTED
+    } else {
+        code
+    })
+}
+
+// Arguments that select the config files and change their tests, for a run and a plan
+fn config_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("config-file")
+            .short("c")
+            .long("config-file")
+            // .value_name("FILE")
+            .help("Configuration file containing tests to be run. Can be repeated, a directory runs every *.fcheck.toml and *.fcheck.dhall file in it")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(true),
+        Arg::with_name("parallel")
+            .long("parallel")
+            .help("Run the tests in parallel")
+            .required(false),
+        Arg::with_name("max-parallel")
+            .long("max-parallel")
+            .help("Maximum number of tests to run at the same time. Implies --parallel")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .help("Stops starting tests after the first one that fails, the rest are reported as not run and fail the run")
+            .required(false),
+        Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("MS")
+            .help("Timeout of the commands that don't set one in the config, 0 for no timeout")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("var")
+            .long("var")
+            .help("Sets a variable of the config, replacing its value in [vars]. Format: key=value")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("filter")
+            .long("filter")
+            .help("Only runs the tests with a matching name. A glob, or a regex with the prefix re:")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("skip")
+            .long("skip")
+            .help("Skips the tests with a matching name. A glob, or a regex with the prefix re:")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("tag")
+            .long("tag")
+            .help("Only runs the tests with this tag")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("exclude-tag")
+            .long("exclude-tag")
+            .help("Skips the tests with this tag")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+    ]
+}
+
+// Runs the config files of the command line, returns whether every test passed
+fn run_cli(matches: &ArgMatches) -> Result<bool, FcheckError> {
+    if let Some(plan) = matches.subcommand_matches("plan") {
+        print_plan(plan)?;
+        return Ok(true);
+    }
+
+    let report_format = matches.value_of("format").unwrap_or("json");
     let output_report_filepath = matches
         .value_of("report-file")
-        .unwrap_or("./output/report.json");
+        .unwrap_or(match report_format {
+            "junit" => "./output/report.xml",
+            _ => "./output/report.json",
+        });
 
     // match matches.occurrences_of("v") {
     //     0 => println!("No verbose info"),
@@ -59,50 +256,180 @@ fn main() {
     //     3 | _ => println!("Don't be crazy"),
     // }
 
-    let config_path = Path::new(config_file);
-    if !config_path.exists() {
-        println!("config-file not found. (Value provided: {})", config_file);
-        std::process::exit(1)
+    let modules = load_modules(matches)?;
+    for (file, _) in modules.iter() {
+        println!("Config file found: {}.", file);
     }
-
-    let config_file_type = get_extension_from_filename(config_file)
-        .and_then(|ext| file_extension_to_filetype(ext))
-        .expect("Config file has invalid extension type. Valid extensions: .toml, .dhall");
-
-    let config_contents = fs::read_to_string(config_path).expect("Failed to read config file.");
-
-    let module =
-        prepare_file(config_file_type, config_contents).expect("Failed to process config file");
-    println!("Config file found: {}.", config_path.display());
+    let max_parallel_modules = match number_arg(matches, "max-parallel-modules")? {
+        Some(max) => max,
+        None if matches.is_present("parallel-modules") => default_max_parallel(),
+        None => 1,
+    };
+    let slowest = number_arg(matches, "slowest")?.unwrap_or(0);
+    let budget = number_arg(matches, "budget")?;
     println!("Starting....");
 
-    let res = run(&module);
+    let color = !matches.is_present("no-color")
+        && std::env::var_os("NO_COLOR").is_none()
+        && std::io::stdout().is_terminal();
+    let labels = match (modules.len(), max_parallel_modules) {
+        (1, _) => ModuleLabels::None,
+        (_, 1) => ModuleLabels::Headers,
+        _ => ModuleLabels::Prefix,
+    };
+    let reporter = ConsoleReporter::new(color).with_module_labels(labels);
 
-    let report_string = format_module(&res);
-
-    println!("Run Results: \n{} \n", report_string);
+    let on_event = |event: &RunEvent| reporter.on_run_event(event);
+    let run = |runner: &dyn CommandRunner| match budget {
+        Some(budget) => {
+            let runner = BudgetRunner::new(
+                runner,
+                Duration::from_millis(budget as u64),
+                &format!("Run budget of {}ms used up", budget),
+            );
+            run_all(&modules, max_parallel_modules, &runner, &on_event)
+        }
+        None => run_all(&modules, max_parallel_modules, runner, &on_event),
+    };
+    let res = if let Some(path) = matches.value_of("record") {
+        let runner = RecordingRunner::new(LocalRunner);
+        let res = run(&runner);
+        runner
+            .save(Path::new(path))
+            .map_err(|error| FcheckError::Io {
+                context: format!("Failed to write recording {}", path),
+                error,
+            })?;
+        res
+    } else {
+        let runner: Box<dyn CommandRunner> =
+            match matches.value_of("replay") {
+                Some(path) => Box::new(ReplayRunner::load(Path::new(path)).map_err(|error| {
+                    FcheckError::Io {
+                        context: format!("Failed to read recording {}", path),
+                        error,
+                    }
+                })?),
+                None if matches.is_present("dry-run") => Box::new(DryRunRunner),
+                None => Box::new(LocalRunner),
+            };
+        run(runner.as_ref())
+    };
 
-    let output_report_path = Path::new(output_report_filepath);
+    // A single config file keeps the report of one module
+    let report_string = match (report_format, &res.modules[..]) {
+        ("junit", [module]) => format_module_junit(&module.result),
+        ("junit", _) => format_run_junit(&res),
+        (_, [module]) => format_module(&module.result),
+        _ => format_run(&res),
+    };
 
-    let output_report_parent = output_report_path
-        .parent()
-        .expect("Couldn't get report path parent");
+    if res.modules.len() > 1 {
+        reporter.print_run_summary(&res);
+    }
+    reporter.print_slowest(&res, slowest);
 
-    fs::create_dir_all(&output_report_parent.display().to_string());
-    fs::write(output_report_path, &report_string)
-        .expect(&format!("Unable to write report file: {}", &report_string));
+    let output_report_path = Path::new(output_report_filepath);
+    let report_error = |error| FcheckError::ReportWrite {
+        path: output_report_filepath.to_string(),
+        error,
+    };
+    if let Some(parent) = output_report_path.parent() {
+        fs::create_dir_all(parent).map_err(report_error)?;
+    }
+    fs::write(output_report_path, &report_string).map_err(report_error)?;
     println!(
         "Run results report written to: {}",
         output_report_path.display()
     );
 
-    if res.success() {
-        std::process::exit(0)
-    } else {
-        std::process::exit(1)
+    Ok(res.success())
+}
+
+// Parses and resolves the config files of the command line, with the tests
+// selected and the parallel settings applied
+fn load_modules(matches: &ArgMatches) -> Result<Vec<(String, ProcessingModule)>, FcheckError> {
+    let config_files: Vec<&str> = matches
+        .values_of("config-file")
+        .into_iter()
+        .flatten()
+        .collect();
+    config::load_modules(&config_files, &load_options(matches)?)
+}
+
+fn load_options(matches: &ArgMatches) -> Result<LoadOptions, FcheckError> {
+    let mut var_overrides = Variables::new();
+    for var in matches.values_of("var").into_iter().flatten() {
+        match var.split_once('=') {
+            Some((key, value)) => var_overrides.insert(key.to_string(), value.to_string()),
+            None => {
+                return Err(FcheckError::InvalidArgument(format!(
+                    "var must be in the format key=value. (Value provided: {})",
+                    var
+                )))
+            }
+        };
     }
+
+    let patterns = |name: &str| -> Result<Vec<NamePattern>, FcheckError> {
+        matches
+            .values_of(name)
+            .into_iter()
+            .flatten()
+            .map(|pattern| {
+                NamePattern::new(pattern).map_err(|err| {
+                    FcheckError::InvalidArgument(format!(
+                        "{} is not a valid pattern. {}",
+                        name, err
+                    ))
+                })
+            })
+            .collect()
+    };
+    let values = |name: &str| -> Vec<String> {
+        matches
+            .values_of(name)
+            .into_iter()
+            .flatten()
+            .map(String::from)
+            .collect()
+    };
+
+    Ok(LoadOptions {
+        var_overrides,
+        filter: TestFilter {
+            names: patterns("filter")?,
+            skip: patterns("skip")?,
+            tags: values("tag"),
+            exclude_tags: values("exclude-tag"),
+        },
+        timeout: number_arg(matches, "timeout")?.map(|timeout| timeout as u64),
+        fail_fast: matches.is_present("fail-fast"),
+        parallel: matches.is_present("parallel"),
+        max_parallel: number_arg(matches, "max-parallel")?,
+    })
+}
+
+fn number_arg(matches: &ArgMatches, name: &str) -> Result<Option<usize>, FcheckError> {
+    matches
+        .value_of(name)
+        .map(|max| {
+            max.parse::<usize>().map_err(|_| {
+                FcheckError::InvalidArgument(format!(
+                    "{} must be a number. (Value provided: {})",
+                    name, max
+                ))
+            })
+        })
+        .transpose()
 }
 
-fn get_extension_from_filename(filename: &str) -> Option<&str> {
-    Path::new(filename).extension().and_then(OsStr::to_str)
+// Prints what the config files would run, nothing is run
+fn print_plan(matches: &ArgMatches) -> Result<(), FcheckError> {
+    let modules = load_modules(matches)?;
+    match matches.value_of("format") {
+        Some("json") => println!("{}", format_plan_json(&modules)),
+        _ => print!("{}", format_plan_text(&modules)),
+    }
+    Ok(())
 }
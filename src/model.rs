@@ -1,3 +1,9 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Values captured from command output, by variable name
+pub type Variables = BTreeMap<String, String>;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Shell(pub String, pub Vec<String>);
 impl Default for Shell {
@@ -10,62 +16,426 @@ impl Default for Shell {
 pub struct ProcessingModule {
     pub shell: Shell,
     pub setup: CommandSet,
+    // Started after setup, stopped in reverse order after teardown
+    pub services: Vec<Service>,
     pub tests: CommandFamily,
     pub teardown: CommandSet,
+    pub teardown_policy: TeardownPolicy,
+}
+impl ProcessingModule {
+    // Gives a timeout to the commands that have none, services are left
+    // running as long as the tests need them
+    pub fn set_default_timeout(&mut self, timeout: u64) {
+        let sets = std::iter::once(&mut self.setup)
+            .chain(self.tests.sets.iter_mut())
+            .chain(std::iter::once(&mut self.teardown));
+        let commands = sets.flat_map(|set| {
+            set.setup
+                .iter_mut()
+                .chain(set.commands.iter_mut())
+                .chain(set.teardown.iter_mut())
+        });
+        for command in commands {
+            command.timeout.get_or_insert(timeout);
+        }
+    }
+}
+
+// When the teardown is run
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum TeardownPolicy {
+    // Even if setup or tests failed, to clean up what was partially created
+    #[default]
+    Always,
+    // Only if setup and all tests succeeded
+    OnSuccess,
+    Never,
+}
+
+// A long-running process the tests depend on, like a server
+#[derive(Debug, PartialEq, Clone)]
+pub struct Service {
+    pub name: String,
+    pub command: ExecutableCommand,
+    pub ready: Option<ReadinessProbe>,
+    // Milliseconds the service has to become ready
+    pub startup_timeout: u64,
+    // Milliseconds between two readiness probes
+    pub probe_interval: u64,
 }
 
+// How to know that a service is ready. Without a probe, a service is ready
+// as soon as it started.
 #[derive(Debug, PartialEq, Clone)]
+pub enum ReadinessProbe {
+    // `host:port` accepts connections
+    Tcp(String),
+    // `http://` URL answers with a 2xx status
+    Http(String),
+    // Regular expression matching a line of stdout or stderr
+    Log(String),
+    // Command exits successfully
+    Command(Box<ExecutableCommand>),
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum ProcessingKind {
+    #[default]
     Serial,
-    // Parallel,
+    // Maximum number of items running at the same time
+    Parallel(usize),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct CommandFamily {
     pub sets: Vec<CommandSet>,
     pub processing_kind: ProcessingKind,
+    // The sets after the first one that failed are skipped
+    pub fail_fast: bool,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum CommandSetType {
     Setup,
+    #[default]
     Test,
     Teardown,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct CommandSet {
     pub name: Option<String>,
+    pub description: Option<String>,
     pub set_type: CommandSetType,
+    // Run before the commands of a test, which are skipped if one fails
+    pub setup: Vec<ExecutableCommand>,
     pub commands: Vec<ExecutableCommand>,
+    // Run after the commands of a test, even when they failed
+    pub teardown: Vec<ExecutableCommand>,
     pub processing_kind: ProcessingKind,
+    pub tags: Vec<String>,
+    // Values of the matrix entry a test was generated from, by parameter
+    pub matrix: Variables,
+    // Why the set is not run, when it is disabled or filtered out
+    pub skip: Option<String>,
+    // Milliseconds for all the commands of the set. When it is used up, the
+    // running command is stopped and the ones after it are not run.
+    pub budget: Option<u64>,
+    // A failure of the set is only a warning
+    pub allow_failure: bool,
+    // Milliseconds the whole set may take, like for a command
+    pub max_duration: Option<u64>,
+    pub warn_duration: Option<u64>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct ExecutableCommand {
     pub name: Option<String>,
     pub description: Option<String>,
+    // Milliseconds, 0 or None for no timeout
     pub timeout: Option<u64>,
+    // Milliseconds between SIGTERM and SIGKILL when the command times out
+    pub grace_period: Option<u64>,
     pub shell: Shell,
     pub cmd: String,
+    // Added to the environment of fcheck, or replacing it with `clear_env`
+    pub env: BTreeMap<String, String>,
+    pub clear_env: bool,
+    // Working directory, fcheck's when None
+    pub cwd: Option<String>,
+    // No stdin redirection when None
+    pub stdin: Option<Stdin>,
+    pub expect: Expectations,
+    pub capture: Option<Capture>,
+    // Run only once when None
+    pub retry: Option<Retry>,
+    // The commands after it still run when it fails
+    pub continue_on_error: bool,
+    // A failure of the command is only a warning, the ones after it still run
+    pub allow_failure: bool,
+    // Milliseconds after which a command that passed fails as slow, the
+    // warn_duration only reports it
+    pub max_duration: Option<u64>,
+    pub warn_duration: Option<u64>,
+}
+
+// Re-runs a failed command, for systems that are only eventually consistent
+#[derive(Debug, PartialEq, Clone)]
+pub struct Retry {
+    // Maximum number of runs after the first one, unlimited when None
+    pub retries: Option<u32>,
+    // Milliseconds before the first retry
+    pub delay: u64,
+    // The delay is multiplied by this after every retry
+    pub backoff: f64,
+    // Milliseconds after the first run when no more retries are started
+    pub until: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Stdin {
+    Text(String),
+    // Path of a file
+    File(String),
+}
+
+// Stores part of the stdout of a command in a variable, which later commands
+// use as `${name}`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Capture {
+    pub name: String,
+    // Removes surrounding whitespace from the captured value
+    pub trim: bool,
+    pub extract: CaptureExtract,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CaptureExtract {
+    // All of stdout
+    Stdout,
+    // The first group of the regular expression, or the whole match without groups
+    Regex(String),
+    // A value of stdout parsed as JSON. Strings are captured without quotes.
+    Json { path: String, pointer: String },
+}
+
+// What the result of a command is checked against
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expectations {
+    // Any of these exit codes is a success
+    pub exit_codes: Vec<u32>,
+    pub stdout: Vec<OutputMatcher>,
+    pub stderr: Vec<OutputMatcher>,
+    // Checked against stdout parsed as JSON
+    pub json: Vec<JsonMatcher>,
+}
+impl Default for Expectations {
+    fn default() -> Expectations {
+        Expectations {
+            exit_codes: vec![0],
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            json: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum OutputMatcher {
+    Contains(String),
+    // Trailing newlines are ignored
+    Equals(String),
+    // Regular expression
+    Matches(String),
+    // `false` expects some output
+    Empty(bool),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct JsonMatcher {
+    // As written in the config, JSONPath or JSON Pointer
+    pub path: String,
+    // JSON Pointer the path resolves to
+    pub pointer: String,
+    pub check: JsonCheck,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum JsonCheck {
+    Equals(serde_json::Value),
+    Exists(bool),
+    Type(JsonType),
+    // Number of items of an array, keys of an object or characters of a string
+    Length(usize),
+    GreaterThan(f64),
+    GreaterOrEqual(f64),
+    LessThan(f64),
+    LessOrEqual(f64),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum JsonType {
+    Null,
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssertionResult {
+    pub assertion: String,
+    pub expected: String,
+    pub actual: String,
+    pub success: bool,
+}
+impl AssertionResult {
+    pub fn message(&self) -> String {
+        format!(
+            "{}: expected {:?}, actual {:?}",
+            self.assertion, self.expected, self.actual
+        )
+    }
+}
+
+// When something ran. The duration is measured on a monotonic clock, so it
+// stays right when the system clock changes during the run.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Timing {
+    pub started: SystemTime,
+    pub duration: Duration,
+}
+impl Timing {
+    pub fn finished(&self) -> SystemTime {
+        self.started + self.duration
+    }
+}
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing {
+            started: UNIX_EPOCH,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+// Why something took longer than a limit in milliseconds, like
+// "took 350ms, more than the max_duration of 200ms"
+fn over_limit(timing: &Timing, limit: Option<u64>, name: &str) -> Option<String> {
+    limit
+        .filter(|limit| timing.duration > Duration::from_millis(*limit))
+        .map(|limit| {
+            format!(
+                "took {}ms, more than the {} of {}ms",
+                timing.duration.as_millis(),
+                name,
+                limit
+            )
+        })
+}
+
+// Measures a Timing from the moment it is started
+pub struct Stopwatch {
+    started: SystemTime,
+    start: Instant,
+}
+impl Stopwatch {
+    pub fn start() -> Stopwatch {
+        Stopwatch {
+            started: SystemTime::now(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn timing(&self) -> Timing {
+        Timing {
+            started: self.started,
+            duration: self.start.elapsed(),
+        }
+    }
+}
+
+// Every config file of one invocation of fcheck
+#[derive(Debug, PartialEq, Clone)]
+pub struct RunResult {
+    pub modules: Vec<ModuleRun>,
+    pub timing: Timing,
+}
+impl RunResult {
+    pub fn success(&self) -> bool {
+        self.modules.iter().all(|m| m.result.success())
+    }
+
+    // The `count` commands that took the longest, with the file and set they ran in
+    pub fn slowest_commands(&self, count: usize) -> Vec<(&str, &CommandSet, &CommandResult)> {
+        let mut commands: Vec<_> = self
+            .modules
+            .iter()
+            .flat_map(|m| {
+                m.result
+                    .set_results()
+                    .map(move |set| (m.file.as_str(), set))
+            })
+            .flat_map(|(file, set)| set.all_results().map(move |res| (file, &set.set, res)))
+            .filter(|(_, _, res)| !matches!(res, CommandResult::NotRun { .. }))
+            .collect();
+        commands.sort_by_key(|(_, _, res)| std::cmp::Reverse(res.timing().duration));
+        commands.truncate(count);
+        commands
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ModuleRun {
+    // Path of the config file
+    pub file: String,
+    pub result: ProcessingModuleResult,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ProcessingModuleResult {
     pub module: ProcessingModule,
     pub setup: CommandSetResult,
+    pub services: Vec<ServiceResult>,
     pub tests: Option<CommandFamilyResult>,
     pub teardown: Option<CommandSetResult>,
+    pub timing: Timing,
 }
 impl ProcessingModuleResult {
+    // Setup, tests and teardown, in the order they ran
+    pub fn set_results(&self) -> impl Iterator<Item = &CommandSetResult> {
+        std::iter::once(&self.setup)
+            .chain(self.tests.iter().flat_map(|t| t.sets.iter()))
+            .chain(self.teardown.iter())
+    }
+
     pub fn success(&self) -> bool {
         self.setup.success()
+            && self.services.iter().all(|s| s.success())
             // Check that tests was Some and .success() is true
             && self.tests.is_some()
             && self.tests.clone().map(|t| t.success()).unwrap_or(false)
-            // Check that teardown was Some and .success() is true
-            && self.teardown.is_some()
-            && self.teardown.clone().map(|t| t.success()).unwrap_or(false)
+            // Teardown is None when skipped by the TeardownPolicy
+            && self.teardown.clone().map(|t| t.success()).unwrap_or(true)
+    }
+
+    pub fn tests_skipped_reason(&self) -> Option<String> {
+        match self.tests {
+            Some(_) => None,
+            None if !self.setup.success() => Some("Setup failed".to_string()),
+            None => self
+                .services
+                .iter()
+                .find(|s| !s.success())
+                .map(|s| format!("Service '{}' failed to start", s.service.name)),
+        }
+    }
+
+    pub fn teardown_skipped_reason(&self) -> Option<String> {
+        match (&self.teardown, &self.module.teardown_policy) {
+            (Some(_), _) => None,
+            (None, TeardownPolicy::OnSuccess) => {
+                Some("Teardown policy is 'on_success' and the setup or tests failed".to_string())
+            }
+            (None, _) => Some("Teardown policy is 'never'".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ServiceResult {
+    pub service: Service,
+    // Why the service failed to start, or that it stopped on its own
+    pub error: Option<String>,
+    // How the service ended, like "stopped with SIGTERM"
+    pub exit_status: String,
+    pub stdout: String,
+    pub stderr: String,
+}
+impl ServiceResult {
+    pub fn success(&self) -> bool {
+        self.error.is_none()
     }
 }
 
@@ -73,21 +443,90 @@ impl ProcessingModuleResult {
 pub struct CommandFamilyResult {
     pub family: CommandFamily,
     pub sets: Vec<CommandSetResult>,
+    pub timing: Timing,
 }
 impl CommandFamilyResult {
     pub fn success(&self) -> bool {
-        self.sets.iter().fold(true, |b, res| b && res.success())
+        self.sets
+            .iter()
+            .all(|res| res.success() || res.allowed_failure())
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct CommandSetResult {
     pub set: CommandSet,
+    pub setup: Vec<CommandResult>,
     pub results: Vec<CommandResult>,
+    pub teardown: Vec<CommandResult>,
+    // Variables captured by the commands of this set
+    pub captures: Variables,
+    pub timing: Timing,
 }
 impl CommandSetResult {
+    // Setup, commands and teardown, in the order they ran
+    pub fn all_results(&self) -> impl Iterator<Item = &CommandResult> {
+        self.setup
+            .iter()
+            .chain(self.results.iter())
+            .chain(self.teardown.iter())
+    }
+
     pub fn success(&self) -> bool {
-        self.results.iter().fold(true, |b, res| b && res.success())
+        self.all_results()
+            .all(|res| res.success() || res.allowed_failure())
+            && self.over_max_duration().is_none()
+    }
+
+    // Failed, but the set allows it
+    pub fn allowed_failure(&self) -> bool {
+        self.set.allow_failure && !self.success()
+    }
+
+    // Why the whole set took longer than its max_duration
+    pub fn over_max_duration(&self) -> Option<String> {
+        over_limit(&self.timing, self.set.max_duration, "max_duration")
+    }
+
+    // Failed only because the set, or some of its commands, took too long
+    pub fn slow(&self) -> bool {
+        !self.success()
+            && self
+                .all_results()
+                .all(|res| res.success() || res.allowed_failure() || res.slow())
+    }
+
+    // Why the set is slow, its own max_duration or that of its first slow command
+    pub fn slow_reason(&self) -> Option<String> {
+        if !self.slow() {
+            return None;
+        }
+        self.over_max_duration()
+            .or_else(|| self.all_results().find_map(|res| res.slow_reason()))
+    }
+
+    // The set passed, but took longer than its warn_duration
+    pub fn duration_warning(&self) -> Option<String> {
+        if !self.success() {
+            return None;
+        }
+        over_limit(&self.timing, self.set.warn_duration, "warn_duration")
+    }
+
+    pub fn skipped(&self) -> bool {
+        self.set.skip.is_some()
+    }
+
+    // Why the set was not started, which fails it, when every one of its
+    // commands was aborted before it ran
+    pub fn not_run(&self) -> Option<String> {
+        let aborted = self
+            .all_results()
+            .all(|res| matches!(res, CommandResult::NotRun { aborted: true, .. }));
+        match self.all_results().next() {
+            Some(CommandResult::NotRun { reason, .. }) if aborted => Some(reason.clone()),
+            _ => None,
+        }
     }
 }
 
@@ -96,29 +535,62 @@ pub enum CommandResult {
     OsError {
         command: ExecutableCommand,
         error: String,
+        timing: Timing,
     },
     RuntimeError {
         command: ExecutableCommand,
         stdout: String,
         stderr: String,
         error: String,
+        timing: Timing,
     },
     Timeout {
         command: ExecutableCommand,
         stdout: String,
         stderr: String,
+        // Signal that stopped the command, SIGTERM or SIGKILL
+        signal: String,
+        // Why it was stopped when it was not its own timeout, like a used up budget
+        reason: Option<String>,
+        timing: Timing,
     },
     IrregularExitCode {
         command: ExecutableCommand,
         stdout: String,
         stderr: String,
         exit_code: String,
+        timing: Timing,
     },
     StandardResult {
         command: ExecutableCommand,
         stdout: String,
         stderr: String,
         exit_code: u32,
+        assertions: Vec<AssertionResult>,
+        timing: Timing,
+    },
+    // A service stopped before or while the command ran
+    ServiceCrashed {
+        command: ExecutableCommand,
+        service: String,
+        exit_status: String,
+        stdout: String,
+        stderr: String,
+        timing: Timing,
+    },
+    // Every run of a command that was retried, the last one is the result
+    Retried {
+        attempts: Vec<CommandResult>,
+        // From the start of the first attempt to the end of the last one
+        timing: Timing,
+    },
+    // The command was not run, like in a dry run
+    NotRun {
+        command: ExecutableCommand,
+        reason: String,
+        // Stopped by a budget, which is a failure, rather than skipped
+        aborted: bool,
+        timing: Timing,
     },
 }
 impl CommandResult {
@@ -127,30 +599,151 @@ impl CommandResult {
             CommandResult::OsError {
                 command: _,
                 error: _,
+                timing: _,
             } => false,
             CommandResult::RuntimeError {
                 command: _,
                 stdout: _,
                 stderr: _,
                 error: _,
+                timing: _,
             } => false,
             CommandResult::Timeout {
                 command: _,
                 stdout: _,
                 stderr: _,
+                signal: _,
+                reason: _,
+                timing: _,
             } => false,
             CommandResult::IrregularExitCode {
                 command: _,
                 stdout: _,
                 stderr: _,
                 exit_code: _,
+                timing: _,
             } => false,
             CommandResult::StandardResult {
-                command: _,
+                command,
                 stdout: _,
                 stderr: _,
                 exit_code,
-            } => exit_code.eq(&0),
+                assertions,
+                timing,
+            } => {
+                command.expect.exit_codes.contains(exit_code)
+                    && assertions.iter().all(|a| a.success)
+                    && over_limit(timing, command.max_duration, "max_duration").is_none()
+            }
+            CommandResult::ServiceCrashed { .. } => false,
+            CommandResult::Retried { .. } => self.last_attempt().success(),
+            CommandResult::NotRun { aborted, .. } => !aborted,
+        }
+    }
+
+    // Failed, but the command allows it
+    pub fn allowed_failure(&self) -> bool {
+        self.command().allow_failure && !self.success()
+    }
+
+    // Why a command that passed otherwise failed for taking too long
+    pub fn slow_reason(&self) -> Option<String> {
+        match self {
+            CommandResult::StandardResult {
+                command,
+                exit_code,
+                assertions,
+                timing,
+                ..
+            } if command.expect.exit_codes.contains(exit_code)
+                && assertions.iter().all(|a| a.success) =>
+            {
+                over_limit(timing, command.max_duration, "max_duration")
+            }
+            CommandResult::Retried { .. } => self.last_attempt().slow_reason(),
+            _ => None,
+        }
+    }
+
+    pub fn slow(&self) -> bool {
+        self.slow_reason().is_some()
+    }
+
+    // The command passed, but took longer than its warn_duration
+    pub fn duration_warning(&self) -> Option<String> {
+        match self.last_attempt() {
+            res @ CommandResult::StandardResult {
+                command, timing, ..
+            } if res.success() => over_limit(timing, command.warn_duration, "warn_duration"),
+            _ => None,
+        }
+    }
+
+    // The result of the last run, when the command was retried
+    pub fn last_attempt(&self) -> &CommandResult {
+        match self {
+            CommandResult::Retried { attempts, .. } => attempts
+                .last()
+                .expect("A retried command has attempts")
+                .last_attempt(),
+            _ => self,
+        }
+    }
+
+    pub fn timing(&self) -> Timing {
+        match self {
+            CommandResult::OsError { timing, .. }
+            | CommandResult::RuntimeError { timing, .. }
+            | CommandResult::Timeout { timing, .. }
+            | CommandResult::IrregularExitCode { timing, .. }
+            | CommandResult::StandardResult { timing, .. }
+            | CommandResult::ServiceCrashed { timing, .. }
+            | CommandResult::Retried { timing, .. }
+            | CommandResult::NotRun { timing, .. } => *timing,
+        }
+    }
+
+    pub fn attempt_count(&self) -> usize {
+        match self {
+            CommandResult::Retried { attempts, .. } => attempts.len(),
+            _ => 1,
+        }
+    }
+
+    pub fn command(&self) -> &ExecutableCommand {
+        match self {
+            CommandResult::OsError { command, .. }
+            | CommandResult::RuntimeError { command, .. }
+            | CommandResult::Timeout { command, .. }
+            | CommandResult::IrregularExitCode { command, .. }
+            | CommandResult::StandardResult { command, .. }
+            | CommandResult::ServiceCrashed { command, .. }
+            | CommandResult::NotRun { command, .. } => command,
+            CommandResult::Retried { .. } => self.last_attempt().command(),
+        }
+    }
+
+    pub fn stdout(&self) -> &str {
+        match self {
+            CommandResult::OsError { .. } | CommandResult::NotRun { .. } => "",
+            CommandResult::RuntimeError { stdout, .. }
+            | CommandResult::Timeout { stdout, .. }
+            | CommandResult::IrregularExitCode { stdout, .. }
+            | CommandResult::StandardResult { stdout, .. }
+            | CommandResult::ServiceCrashed { stdout, .. } => stdout,
+            CommandResult::Retried { .. } => self.last_attempt().stdout(),
+        }
+    }
+
+    pub fn stderr(&self) -> &str {
+        match self {
+            CommandResult::OsError { .. } | CommandResult::NotRun { .. } => "",
+            CommandResult::RuntimeError { stderr, .. }
+            | CommandResult::Timeout { stderr, .. }
+            | CommandResult::IrregularExitCode { stderr, .. }
+            | CommandResult::StandardResult { stderr, .. }
+            | CommandResult::ServiceCrashed { stderr, .. } => stderr,
+            CommandResult::Retried { .. } => self.last_attempt().stderr(),
         }
     }
 }
@@ -1,28 +1,84 @@
 // use std::str::{from_utf8};
 // use serde_json::*; //::to_string_pretty;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde_derive::Serialize;
 
 use crate::model::{
+    AssertionResult,
     // ExecutableCommand,
     CommandResult,
     CommandSetResult,
     // CommandFamilyResult,
     ProcessingModuleResult,
+    RunResult,
+    ServiceResult,
+    Timing,
+    Variables,
 };
 
+// Report of a run with several config files
+#[derive(Serialize, Debug)]
+pub struct RunOutput {
+    result: String,
+    #[serde(flatten)]
+    timing: TimingOutput,
+    modules: Vec<ModuleRunOutput>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModuleRunOutput {
+    file: String,
+    #[serde(flatten)]
+    module: ModuleOutput,
+}
+
 #[derive(Serialize, Debug)]
 pub struct ModuleOutput {
     result: String,
+    #[serde(flatten)]
+    timing: TimingOutput,
     setup: Vec<CommandOutput>,
+    // Captured in setup, available to every test
+    captures: Variables,
+    services: Vec<ServiceOutput>,
     tests: Option<Vec<TestOutput>>,
+    tests_skipped: Option<String>,
     teardown: Option<Vec<CommandOutput>>,
+    teardown_skipped: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServiceOutput {
+    name: String,
+    command: String,
+    result: String,
+    error: Option<String>,
+    exit_status: String,
+    stdout: String,
+    stderr: String,
 }
 
 #[derive(Serialize, Debug)]
 pub struct TestOutput {
     name: Option<String>,
+    description: Option<String>,
     result: String,
+    #[serde(flatten)]
+    timing: TimingOutput,
+    tags: Vec<String>,
+    // Values of the matrix the test was generated from
+    matrix: Variables,
+    skipped: Option<String>,
+    // Why a test that passed otherwise failed for taking too long
+    slow: Option<String>,
+    warning: Option<String>,
+    // The before_each hooks and the setup of the test
+    setup: Vec<CommandOutput>,
     commands: Vec<CommandOutput>,
+    // The teardown of the test and the after_each hooks
+    teardown: Vec<CommandOutput>,
+    captures: Variables,
 }
 
 #[derive(Serialize, Debug, PartialEq)]
@@ -32,12 +88,16 @@ pub enum CommandOutput {
         name: Option<String>,
         command: String,
         result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
         error: String,
     },
     RuntimeError {
         name: Option<String>,
         command: String,
         result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
         stdout: String,
         stderr: String,
         error: String,
@@ -46,13 +106,20 @@ pub enum CommandOutput {
         name: Option<String>,
         command: String,
         result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
         stdout: String,
         stderr: String,
+        signal: String,
+        // Why it was stopped when it was not its own timeout
+        reason: Option<String>,
     },
     IrregularExitCode {
         name: Option<String>,
         command: String,
         result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
         stdout: String,
         stderr: String,
         exit_code: String,
@@ -61,17 +128,85 @@ pub enum CommandOutput {
         name: Option<String>,
         command: String,
         result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
         stdout: String,
         stderr: String,
         exit_code: u32,
+        expected_exit_code: Vec<u32>,
+        assertions: Vec<AssertionOutput>,
+        slow: Option<String>,
+        warning: Option<String>,
+    },
+    ServiceCrashed {
+        name: Option<String>,
+        command: String,
+        result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
+        stdout: String,
+        stderr: String,
+        service: String,
+        exit_status: String,
+    },
+    Retried {
+        name: Option<String>,
+        command: String,
+        result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
+        attempt_count: usize,
+        attempts: Vec<CommandOutput>,
+    },
+    NotRun {
+        name: Option<String>,
+        command: String,
+        result: String,
+        #[serde(flatten)]
+        timing: TimingOutput,
+        reason: String,
+        // Stopped by a budget rather than skipped, which fails the test
+        aborted: bool,
     },
 }
 
+// When something ran, the timestamps are UTC
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TimingOutput {
+    started: String,
+    finished: String,
+    duration_ms: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AssertionOutput {
+    assertion: String,
+    result: String,
+    expected: String,
+    actual: String,
+}
+
 pub fn format_module(module: &ProcessingModuleResult) -> String {
     let mod_out = map_module(module);
     to_json(&mod_out)
 }
 
+pub fn format_run(run: &RunResult) -> String {
+    let run_out = RunOutput {
+        result: result_to_string(run.success()),
+        timing: map_timing(&run.timing),
+        modules: run
+            .modules
+            .iter()
+            .map(|m| ModuleRunOutput {
+                file: m.file.clone(),
+                module: map_module(&m.result),
+            })
+            .collect(),
+    };
+    to_json(&run_out)
+}
+
 fn result_to_string(b: bool) -> String {
     if b {
         "success".to_string()
@@ -83,34 +218,79 @@ fn result_to_string(b: bool) -> String {
 fn map_module(module: &ProcessingModuleResult) -> ModuleOutput {
     ModuleOutput {
         result: result_to_string(module.success()),
+        timing: map_timing(&module.timing),
         setup: module.setup.results.iter().map(map_command).collect(),
+        captures: module.setup.captures.clone(),
+        services: module.services.iter().map(map_service).collect(),
         tests: module
             .tests
             .clone()
             .map(|t| t.sets.iter().map(map_test).collect()),
+        tests_skipped: module.tests_skipped_reason(),
         teardown: module
             .teardown
             .clone()
             .map(|t| t.results.iter().map(map_command).collect()),
+        teardown_skipped: module.teardown_skipped_reason(),
+    }
+}
+
+fn map_service(service: &ServiceResult) -> ServiceOutput {
+    ServiceOutput {
+        name: service.service.name.clone(),
+        command: service.service.command.cmd.clone(),
+        result: result_to_string(service.success()),
+        error: service.error.clone(),
+        exit_status: service.exit_status.clone(),
+        stdout: service.stdout.clone(),
+        stderr: service.stderr.clone(),
     }
 }
 
 fn map_test(set: &CommandSetResult) -> TestOutput {
     TestOutput {
         name: set.set.name.clone(),
-        result: result_to_string(set.success()),
+        description: set.set.description.clone(),
+        result: if set.skipped() {
+            "skipped".to_string()
+        } else if set.not_run().is_some() {
+            "not run".to_string()
+        } else if set.allowed_failure() {
+            "warning".to_string()
+        } else if set.slow() {
+            "slow".to_string()
+        } else {
+            result_to_string(set.success())
+        },
+        timing: map_timing(&set.timing),
+        tags: set.set.tags.clone(),
+        matrix: set.set.matrix.clone(),
+        skipped: set.set.skip.clone(),
+        slow: set.slow_reason(),
+        warning: set.duration_warning(),
+        setup: set.setup.iter().map(map_command).collect(),
         commands: set.results.iter().map(map_command).collect(),
+        teardown: set.teardown.iter().map(map_command).collect(),
+        captures: set.captures.clone(),
     }
 }
 
 fn map_command(res: &CommandResult) -> CommandOutput {
-    let result = result_to_string(res.success());
+    let result = if res.allowed_failure() {
+        "warning".to_string()
+    } else if res.slow() {
+        "slow".to_string()
+    } else {
+        result_to_string(res.success())
+    };
+    let timing = map_timing(&res.timing());
 
     match res {
-        CommandResult::OsError { command, error } => CommandOutput::OsError {
+        CommandResult::OsError { command, error, .. } => CommandOutput::OsError {
             name: command.name.clone(),
             command: command.cmd.clone(),
-            result: result,
+            result,
+            timing,
             error: error.clone(),
         },
         CommandResult::RuntimeError {
@@ -118,10 +298,12 @@ fn map_command(res: &CommandResult) -> CommandOutput {
             stdout,
             stderr,
             error,
+            ..
         } => CommandOutput::RuntimeError {
             name: command.name.clone(),
             command: command.cmd.clone(),
-            result: result,
+            result,
+            timing,
             stdout: stdout.clone(),
             stderr: stderr.clone(),
             error: error.clone(),
@@ -130,22 +312,30 @@ fn map_command(res: &CommandResult) -> CommandOutput {
             command,
             stdout,
             stderr,
+            signal,
+            reason,
+            ..
         } => CommandOutput::Timeout {
             name: command.name.clone(),
             command: command.cmd.clone(),
-            result: result,
+            result,
+            timing,
             stdout: stdout.clone(),
             stderr: stderr.clone(),
+            signal: signal.clone(),
+            reason: reason.clone(),
         },
         CommandResult::IrregularExitCode {
             command,
             stdout,
             stderr,
             exit_code,
+            ..
         } => CommandOutput::IrregularExitCode {
             name: command.name.clone(),
             command: command.cmd.clone(),
-            result: result,
+            result,
+            timing,
             stdout: stdout.clone(),
             stderr: stderr.clone(),
             exit_code: exit_code.clone(),
@@ -155,17 +345,125 @@ fn map_command(res: &CommandResult) -> CommandOutput {
             stdout,
             stderr,
             exit_code,
+            assertions,
+            ..
         } => CommandOutput::Complete {
             name: command.name.clone(),
             command: command.cmd.clone(),
-            result: result,
+            result,
+            timing,
             stdout: stdout.clone(),
             stderr: stderr.clone(),
-            exit_code: exit_code.clone(),
+            exit_code: *exit_code,
+            expected_exit_code: command.expect.exit_codes.clone(),
+            assertions: assertions.iter().map(map_assertion).collect(),
+            slow: res.slow_reason(),
+            warning: res.duration_warning(),
+        },
+        CommandResult::ServiceCrashed {
+            command,
+            service,
+            exit_status,
+            stdout,
+            stderr,
+            ..
+        } => CommandOutput::ServiceCrashed {
+            name: command.name.clone(),
+            command: command.cmd.clone(),
+            result,
+            timing,
+            stdout: stdout.clone(),
+            stderr: stderr.clone(),
+            service: service.clone(),
+            exit_status: exit_status.clone(),
+        },
+        CommandResult::Retried { attempts, .. } => CommandOutput::Retried {
+            name: res.command().name.clone(),
+            command: res.command().cmd.clone(),
+            result,
+            timing,
+            attempt_count: attempts.len(),
+            attempts: attempts.iter().map(map_command).collect(),
+        },
+        CommandResult::NotRun {
+            command,
+            reason,
+            aborted,
+            ..
+        } => CommandOutput::NotRun {
+            name: command.name.clone(),
+            command: command.cmd.clone(),
+            result: "not run".to_string(),
+            timing,
+            reason: reason.clone(),
+            aborted: *aborted,
         },
     }
 }
 
-pub fn to_json(module: &ModuleOutput) -> String {
-    serde_json::to_string_pretty(module).expect("Failed to serialize string")
+fn map_assertion(assertion: &AssertionResult) -> AssertionOutput {
+    AssertionOutput {
+        assertion: assertion.assertion.clone(),
+        result: result_to_string(assertion.success),
+        expected: assertion.expected.clone(),
+        actual: assertion.actual.clone(),
+    }
+}
+
+pub fn to_json<T: serde::Serialize>(output: &T) -> String {
+    serde_json::to_string_pretty(output).expect("Failed to serialize string")
+}
+
+fn map_timing(timing: &Timing) -> TimingOutput {
+    TimingOutput {
+        started: format_timestamp(timing.started),
+        finished: format_timestamp(timing.finished()),
+        duration_ms: timing.duration.as_millis() as u64,
+    }
+}
+
+// RFC 3339 in UTC with milliseconds, like 2020-03-01T09:05:00.250Z
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Converts days since 1970-01-01 to a date of the proleptic Gregorian
+    // calendar, counting in eras of 400 years that start on March 1st
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[test]
+fn t_format_timestamp() {
+    use std::time::Duration;
+
+    let at = |millis: u64| format_timestamp(UNIX_EPOCH + Duration::from_millis(millis));
+    assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(at(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    assert_eq!(at(1_583_053_500_250), "2020-03-01T09:05:00.250Z");
+    assert_eq!(at(4_102_444_799_999), "2099-12-31T23:59:59.999Z");
 }
@@ -1,13 +1,15 @@
-use serde_derive::Deserialize;
+use crate::assertions::json_path_to_pointer;
 use crate::model::{
-    Shell,
-    ProcessingModule, 
-    ProcessingKind, 
-    CommandFamily, 
-    CommandSetType, 
-    CommandSet,
-    ExecutableCommand,
-    };
+    Capture, CaptureExtract, CommandFamily, CommandSet, CommandSetType, ExecutableCommand,
+    Expectations, JsonCheck, JsonMatcher, JsonType, OutputMatcher, ProcessingKind,
+    ProcessingModule, ReadinessProbe, Retry, Service, Shell, Stdin, TeardownPolicy, Variables,
+};
+use crate::variables::{is_valid_name, substitute};
+use regex::Regex;
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 pub enum FileType {
     Toml,
@@ -22,19 +24,68 @@ pub struct DefaultShell {
 
 #[derive(Deserialize, Debug)]
 pub struct TestModule {
+    #[allow(dead_code)]
     version: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     shell: Option<DefaultShell>,
-    
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parallel: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_parallel: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vars: Option<Variables>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<BTreeMap<String, String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clear_env: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdin: Option<StdinConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_period: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     setup: Option<Vec<Command>>,
-    
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before_each: Option<Vec<Command>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after_each: Option<Vec<Command>>,
+
+    #[serde(alias = "service")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services: Option<Vec<ServiceConfig>>,
+
     #[serde(alias = "test")]
     tests: Vec<Test>,
-    
+
     #[serde(skip_serializing_if = "Option::is_none")]
     teardown: Option<Vec<Command>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    teardown_policy: Option<TeardownMode>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TeardownMode {
+    Always,
+    OnSuccess,
+    Never,
 }
 
 #[derive(Deserialize, Debug)]
@@ -44,45 +95,337 @@ pub struct Test {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     description: Option<String>,
-    
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<BTreeMap<String, String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clear_env: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdin: Option<StdinConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_period: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_failure: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_duration: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warn_duration: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matrix: Option<MatrixConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    setup: Option<Vec<Command>>,
+
     #[serde(alias = "command")]
     commands: Vec<Command>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    teardown: Option<Vec<Command>>,
+}
+
+// Lists of values by parameter, the test runs once for every combination.
+// `exclude` and `include` work like in the matrix of a CI pipeline.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct MatrixConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclude: Option<Vec<Variables>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include: Option<Vec<Variables>>,
+
+    #[serde(flatten)]
+    params: BTreeMap<String, Vec<String>>,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct Command {
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
-    
+
     #[serde(skip_serializing_if = "Option::is_none")]
     description: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
-    
+
+    #[serde(alias = "cmd")]
+    command: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<BTreeMap<String, String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clear_env: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdin: Option<StdinConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_period: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retries: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_delay: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backoff: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    continue_on_error: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_failure: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_duration: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warn_duration: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expect: Option<Expect>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture: Option<CaptureConfig>,
+}
+
+// A process that runs in the background while the tests run
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ServiceConfig {
+    name: String,
+
     #[serde(alias = "cmd")]
     command: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<BTreeMap<String, String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clear_env: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdin: Option<StdinConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_period: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    startup_timeout: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ready: Option<ReadyConfig>,
+}
+
+// How to tell a service is ready, exactly one of tcp, http, log or command
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ReadyConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+}
+
+// `stdin = "text"` or `stdin = { file = "path" }`
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum StdinConfig {
+    Text(String),
+    File { file: String },
+}
+
+// `capture = "name"` stores all of stdout, a table can extract part of it
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum CaptureConfig {
+    Name(String),
+    Options(CaptureOptions),
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct CaptureOptions {
+    name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trim: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regex: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json: Option<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Default)]
+pub struct Expect {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<ExitCodes>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout_contains: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout_equals: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout_matches: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout_empty: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr_contains: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr_equals: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr_matches: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr_empty: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json: Option<Vec<JsonExpect>>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Default)]
+pub struct JsonExpect {
+    path: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    equals: Option<serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exists: Option<bool>,
+
+    #[serde(rename = "type")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_type: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gt: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gte: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lt: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lte: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ExitCodes {
+    One(u32),
+    Many(Vec<u32>),
 }
 
 #[derive(Debug, PartialEq)]
 pub struct ParseError {
-  description: String,
-  line_col: Option<(usize, usize)>,
+    description: String,
+    line_col: Option<(usize, usize)>,
+}
+
+impl ParseError {
+    // Line and column of the error in the config file, both from 0
+    pub fn line_col(&self) -> Option<(usize, usize)> {
+        self.line_col
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
 }
 
 // Parse and return a ProcessingModule
 
 pub fn prepare_file(
-    file_type: FileType, 
-    config_file: String) 
-        -> Result<ProcessingModule, ParseError> {
+    file_type: FileType,
+    config_file: String,
+    var_overrides: &Variables,
+) -> Result<ProcessingModule, ParseError> {
+    let module = match file_type {
+        FileType::Toml => parse_toml(config_file)?,
+        FileType::Dhall => parse_dhall(config_file)?,
+    };
+    module_with_overrides(module, var_overrides)
+}
 
+// Like prepare_file, for the config file at `path`
+pub fn prepare_config_file(
+    file_type: FileType,
+    path: &Path,
+    config_file: String,
+    var_overrides: &Variables,
+) -> Result<ProcessingModule, ParseError> {
     let module = match file_type {
         FileType::Toml => parse_toml(config_file)?,
-        FileType::Dhall => panic!("Dhall not supported yet."),
+        FileType::Dhall => parse_dhall_file(path)?,
     };
+    module_with_overrides(module, var_overrides)
+}
 
-    Ok(testmodule_to_processingmodel(module))
+fn module_with_overrides(
+    mut module: TestModule,
+    var_overrides: &Variables,
+) -> Result<ProcessingModule, ParseError> {
+    // Variables from the command line replace those of the config
+    module
+        .vars
+        .get_or_insert_with(Variables::new)
+        .extend(var_overrides.clone());
+
+    testmodule_to_processingmodel(module)
 }
 
 pub fn file_extension_to_filetype(ext: &str) -> Option<FileType> {
@@ -91,25 +434,64 @@ pub fn file_extension_to_filetype(ext: &str) -> Option<FileType> {
         "dhall" => Some(FileType::Dhall),
         _ => Option::None,
     }
-} 
+}
+
+// Suffixes of the config files found in a directory
+pub const CONFIG_FILE_SUFFIXES: [&str; 2] = [".fcheck.toml", ".fcheck.dhall"];
+
+// Config files in a directory and its subdirectories, sorted by path.
+// Hidden directories are skipped.
+pub fn find_config_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if path.is_dir() {
+            if !name.starts_with('.') {
+                files.extend(find_config_files(&path)?);
+            }
+        } else if CONFIG_FILE_SUFFIXES
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
 
 // TOML Parser
 
 fn parse_toml(config: String) -> Result<TestModule, ParseError> {
-    toml::from_str(&config)
-      .map_err(|e| {
-        println!("HELLO {}", e);
+    toml::from_str(&config).map_err(|e| ParseError {
+        description: e.to_string(),
+        line_col: e.line_col(),
+    })
+}
 
-        ParseError {
-          description: e.to_string(),
-          line_col: e.line_col(),
-        }
-      })
+// Dhall Parser
+
+fn parse_dhall(config: String) -> Result<TestModule, ParseError> {
+    serde_dhall::from_str(&config).parse().map_err(dhall_error)
+}
+
+// Relative imports are resolved against the directory of the file
+fn parse_dhall_file(path: &Path) -> Result<TestModule, ParseError> {
+    serde_dhall::from_file(path).parse().map_err(dhall_error)
+}
+
+fn dhall_error(e: serde_dhall::Error) -> ParseError {
+    ParseError {
+        description: e.to_string(),
+        line_col: None,
+    }
 }
 
 #[test]
 fn t_basics() {
-    let config = parse_toml(r#"
+    let config = parse_toml(
+        r#"
         version = "3"
 
         [shell]
@@ -142,39 +524,46 @@ curl google.com"""
         [[teardown]]
         command = "def"
 
-    "#.to_string()).unwrap();
+    "#
+        .to_string(),
+    )
+    .unwrap();
 
     assert_eq!(config.version, "3");
 
     assert!(config.shell.is_some());
-    config.shell.map(|shell| {
+    if let Some(shell) = config.shell {
         assert_eq!(shell.path, "/bin/bash");
         assert_eq!(shell.args, vec!("-c"));
-    });
-    
-    config.setup.map(|a| {
+    }
+
+    if let Some(a) = config.setup {
         assert_eq!(a[0].name, Some("setup 1".to_string()));
         assert_eq!(a[0].command, "abc");
         assert_eq!(a[1].command, "def");
-    });
+    }
 
     assert_eq!(config.tests[0].name, Some("test 1".to_string()));
     assert_eq!(config.tests[0].commands[0].name, Some("curl".to_string()));
     assert_eq!(config.tests[0].commands[0].timeout, Some(1000));
     assert_eq!(config.tests[0].commands[0].command, "curl google.com");
     assert_eq!(config.tests[0].commands[1].name, Some("ping".to_string()));
-    assert_eq!(config.tests[0].commands[1].command, "ping google.com;\ncurl google.com");
+    assert_eq!(
+        config.tests[0].commands[1].command,
+        "ping google.com;\ncurl google.com"
+    );
 
-    config.teardown.map(|a| {
+    if let Some(a) = config.teardown {
         assert_eq!(a[0].name, Some("teardown 1".to_string()));
         assert_eq!(a[0].command, "abc");
         assert_eq!(a[1].command, "def");
-    });
+    }
 }
 
 #[test]
 fn t_setup_and_teardown_optional() {
-    let config = parse_toml(r#"
+    let config = parse_toml(
+        r#"
         version = "3"
 
         [[test]]
@@ -183,7 +572,10 @@ fn t_setup_and_teardown_optional() {
         name = "curl"
         cmd = "curl google.com"
 
-    "#.to_string()).unwrap();
+    "#
+        .to_string(),
+    )
+    .unwrap();
 
     assert_eq!(config.setup, Option::None);
     assert_eq!(config.teardown, Option::None);
@@ -191,79 +583,842 @@ fn t_setup_and_teardown_optional() {
 
 #[test]
 fn t_parse_error() {
-    let err: ParseError = parse_toml(r#"
+    let err: ParseError = parse_toml(
+        r#"
         version = "3"
 
         garbage
 
-    "#.to_string()).expect_err("Should have failed");
+    "#
+        .to_string(),
+    )
+    .expect_err("Should have failed");
 
-    assert_eq!(err, ParseError {
-          description: "expected an equals, found a newline at line 4".to_string(),
-          line_col: Some((3, 15)),
-        });
+    assert_eq!(
+        err,
+        ParseError {
+            description: "expected an equals, found a newline at line 4".to_string(),
+            line_col: Some((3, 15)),
+        }
+    );
 }
 
-// Maping from External API to Internal Model
-
-fn testmodule_to_processingmodel(module: TestModule) -> ProcessingModule {
-    let shell = match module.shell {
-        Some(DefaultShell{path, args}) => Shell(path, args),
-        None => Shell("/bin/bash".to_string(), vec!("-c".to_string())),
-    };
-    
-    ProcessingModule {
-        shell: shell.clone(),
-        setup: commandlist_to_commandset(Some("Setup".to_string()), CommandSetType::Setup, &shell, module.setup),
-        tests: testlist_to_commandfamily(&shell, module.tests),
-        teardown: commandlist_to_commandset(Some("Teardown".to_string()), CommandSetType::Teardown, &shell, module.teardown),
-    }
-}
+#[test]
+fn t_dhall_basics() {
+    let config = parse_dhall(
+        r#"
+        let dataDir = "./data"
 
-fn commandlist_to_commandset(name: Option<String>, c_type: CommandSetType, shell: &Shell, opt_commands: Option<Vec<Command>>) -> CommandSet {
-    match opt_commands {
-        Some(commands) => 
-            CommandSet {
-                name: name,
-                set_type: c_type,
-                commands: commands.iter().map(|c| command_to_execommand(shell, c)).collect(),
-                processing_kind: ProcessingKind::Serial,
-            },
-        None => 
-            CommandSet {
-                name: name,
-                set_type: c_type,
-                commands: Vec::new(),
-                processing_kind: ProcessingKind::Serial,
-            },
-    }
-}
+        let write = \(file : Text) -> "echo hello > ${dataDir}/${file}"
 
-fn testlist_to_commandfamily(shell: &Shell, tests: Vec<Test>) -> CommandFamily {
-    let command_sets = tests.iter()
-        .map(|t| {
-            CommandSet {
-                name: t.name.clone(),
-                set_type: CommandSetType::Test,
-                commands: t.commands.iter().map(|c| command_to_execommand(shell, c)).collect(),
-                processing_kind: ProcessingKind::Serial,
+        in  { version = "3"
+            , shell = Some { path = "/bin/sh", args = [ "-c" ] }
+            , setup = Some [ { name = Some "setup 1", command = write "a.txt" } ]
+            , test =
+              [ { name = Some "test 1"
+                , commands =
+                  [ { name = Some "cat", timeout = Some 1000, cmd = "cat ${dataDir}/a.txt" } ]
+                }
+              ]
             }
-        })
-        .collect();
-    
-    CommandFamily {
-        sets: command_sets,
-        processing_kind: ProcessingKind::Serial,
-    }
+    "#
+        .to_string(),
+    )
+    .unwrap();
+
+    assert_eq!(config.version, "3");
+    assert_eq!(config.shell.map(|s| s.path), Some("/bin/sh".to_string()));
+    assert_eq!(
+        config.setup.unwrap()[0].command,
+        "echo hello > ./data/a.txt"
+    );
+    assert_eq!(config.tests[0].name, Some("test 1".to_string()));
+    assert_eq!(config.tests[0].commands[0].timeout, Some(1000));
+    assert_eq!(config.tests[0].commands[0].command, "cat ./data/a.txt");
+    assert_eq!(config.teardown, Option::None);
 }
 
-fn command_to_execommand(shell: &Shell, cmd: &Command) -> ExecutableCommand {
-    ExecutableCommand {
-        name: cmd.name.clone(),
-        description: cmd.description.clone(),
-        timeout: cmd.timeout.clone(),
-        shell: shell.clone(),
-        cmd: cmd.command.clone(),
+#[test]
+fn t_dhall_example_file() {
+    let config = parse_dhall(include_str!("../examples/config.dhall").to_string()).unwrap();
+
+    assert_eq!(
+        config.tests[0].commands[0].command,
+        "rm -f ./data/cats.txt && rm ./data/cats.txt && rm -f ./data/dogs.txt"
+    );
+}
+
+#[test]
+fn t_dhall_type_error() {
+    let err: ParseError = parse_dhall(
+        r#"
+        { version = "3", test = [ { commands = [ { command = 1 + "a" } ] } ] }
+    "#
+        .to_string(),
+    )
+    .expect_err("Should have failed");
+
+    assert_eq!(err.line_col, None);
+    assert!(!err.description.is_empty());
+}
+
+// Maping from External API to Internal Model
+
+fn testmodule_to_processingmodel(module: TestModule) -> Result<ProcessingModule, ParseError> {
+    let mut vars = scope_from_module(&module)?;
+    let mut settings = inherit_settings(
+        &ProcessSettings::default(),
+        &vars,
+        &module.env,
+        module.clear_env,
+        &module.cwd,
+        &module.stdin,
+        module.grace_period,
+    )?;
+    settings.timeout = module.timeout;
+
+    let shell = match &module.shell {
+        Some(DefaultShell { path, args }) => Shell(
+            vars.substitute(path)?,
+            args.iter()
+                .map(|a| vars.substitute(a))
+                .collect::<Result<_, _>>()?,
+        ),
+        None => Shell("/bin/bash".to_string(), vec!["-c".to_string()]),
+    };
+
+    let processing_kind = match (module.parallel, module.max_parallel) {
+        (Some(false), _) | (None, None) => ProcessingKind::Serial,
+        (_, Some(max)) => ProcessingKind::Parallel(max),
+        (Some(true), None) => ProcessingKind::Parallel(default_max_parallel()),
+    };
+
+    // Services don't see captures, they start before the tests
+    let services = module
+        .services
+        .iter()
+        .flatten()
+        .map(|s| service_config_to_service(&shell, &vars, &settings, s))
+        .collect::<Result<_, _>>()?;
+    let setup = commandlist_to_commandset(
+        Some("Setup".to_string()),
+        CommandSetType::Setup,
+        &shell,
+        &mut vars,
+        &settings,
+        module.setup,
+    )?;
+
+    Ok(ProcessingModule {
+        shell: shell.clone(),
+        setup,
+        services,
+        tests: testlist_to_commandfamily(
+            &shell,
+            &vars,
+            &settings,
+            processing_kind,
+            module.before_each.as_deref().unwrap_or_default(),
+            module.tests,
+            module.after_each.as_deref().unwrap_or_default(),
+        )?,
+        teardown: commandlist_to_commandset(
+            Some("Teardown".to_string()),
+            CommandSetType::Teardown,
+            &shell,
+            &mut vars.clone(),
+            &settings,
+            module.teardown,
+        )?,
+        teardown_policy: match module.teardown_policy {
+            Some(TeardownMode::Always) | None => TeardownPolicy::Always,
+            Some(TeardownMode::OnSuccess) => TeardownPolicy::OnSuccess,
+            Some(TeardownMode::Never) => TeardownPolicy::Never,
+        },
+    })
+}
+
+// Variables a config can reference, see `variables::substitute`. A command
+// sees the captures of the module setup, and of the commands before it in
+// its own set.
+#[derive(Clone)]
+struct VarScope {
+    vars: Variables,
+    captures: BTreeSet<String>,
+}
+
+impl VarScope {
+    fn substitute(&self, text: &str) -> Result<String, ParseError> {
+        substitute(text, &self.vars, &self.captures).map_err(|description| ParseError {
+            description,
+            line_col: None,
+        })
+    }
+
+    fn substitute_opt(&self, text: &Option<String>) -> Result<Option<String>, ParseError> {
+        text.as_ref().map(|t| self.substitute(t)).transpose()
+    }
+
+    // Maps the commands in order, each one can use the captures before it
+    fn commands<'a>(
+        &mut self,
+        shell: &Shell,
+        settings: &ProcessSettings,
+        commands: impl IntoIterator<Item = &'a Command>,
+    ) -> Result<Vec<ExecutableCommand>, ParseError> {
+        let mut result = Vec::new();
+        for cmd in commands {
+            result.push(command_to_execommand(shell, self, settings, cmd)?);
+            match &cmd.capture {
+                Some(CaptureConfig::Name(name)) => self.captures.insert(name.clone()),
+                Some(CaptureConfig::Options(options)) => self.captures.insert(options.name.clone()),
+                None => false,
+            };
+        }
+        Ok(result)
+    }
+}
+
+fn scope_from_module(module: &TestModule) -> Result<VarScope, ParseError> {
+    let no_captures = BTreeSet::new();
+    let mut vars = Variables::new();
+    for (name, value) in module.vars.iter().flatten() {
+        if !is_valid_name(name) {
+            return Err(ParseError {
+                description: format!(
+                    "Invalid variable name '{}', use letters, digits and '_'",
+                    name
+                ),
+                line_col: None,
+            });
+        }
+        // Values can use environment variables, but not other variables
+        let value = substitute(value, &Variables::new(), &no_captures).map_err(|e| ParseError {
+            description: format!("{} in variable '{}'", e, name),
+            line_col: None,
+        })?;
+        vars.insert(name.clone(), value);
+    }
+
+    Ok(VarScope {
+        vars,
+        captures: BTreeSet::new(),
+    })
+}
+
+// Environment, working directory and stdin of commands. A module, test and
+// command each inherit the settings of the level above them.
+#[derive(Debug, Clone, Default)]
+struct ProcessSettings {
+    env: BTreeMap<String, String>,
+    clear_env: bool,
+    cwd: Option<String>,
+    stdin: Option<Stdin>,
+    grace_period: Option<u64>,
+    // Milliseconds, 0 for no timeout
+    timeout: Option<u64>,
+}
+
+fn inherit_settings(
+    parent: &ProcessSettings,
+    vars: &VarScope,
+    env: &Option<BTreeMap<String, String>>,
+    clear_env: Option<bool>,
+    cwd: &Option<String>,
+    stdin: &Option<StdinConfig>,
+    grace_period: Option<u64>,
+) -> Result<ProcessSettings, ParseError> {
+    let mut settings = parent.clone();
+    for (name, value) in env.iter().flatten() {
+        settings.env.insert(name.clone(), vars.substitute(value)?);
+    }
+    if let Some(clear_env) = clear_env {
+        settings.clear_env = clear_env;
+    }
+    if let Some(cwd) = cwd {
+        let cwd = vars.substitute(cwd)?;
+        // Relative to the working directory of the level above
+        settings.cwd = Some(match &parent.cwd {
+            Some(parent_cwd) => Path::new(parent_cwd)
+                .join(cwd)
+                .to_string_lossy()
+                .to_string(),
+            None => cwd,
+        });
+    }
+    if let Some(stdin) = stdin {
+        settings.stdin = Some(match stdin {
+            StdinConfig::Text(text) => Stdin::Text(vars.substitute(text)?),
+            StdinConfig::File { file } => Stdin::File(vars.substitute(file)?),
+        });
+    }
+    if grace_period.is_some() {
+        settings.grace_period = grace_period;
+    }
+    Ok(settings)
+}
+
+fn commandlist_to_commandset(
+    name: Option<String>,
+    c_type: CommandSetType,
+    shell: &Shell,
+    vars: &mut VarScope,
+    settings: &ProcessSettings,
+    opt_commands: Option<Vec<Command>>,
+) -> Result<CommandSet, ParseError> {
+    Ok(CommandSet {
+        name,
+        set_type: c_type,
+        commands: vars.commands(shell, settings, opt_commands.iter().flatten())?,
+        ..Default::default()
+    })
+}
+
+pub fn default_max_parallel() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+fn testlist_to_commandfamily(
+    shell: &Shell,
+    vars: &VarScope,
+    settings: &ProcessSettings,
+    processing_kind: ProcessingKind,
+    before_each: &[Command],
+    tests: Vec<Test>,
+    after_each: &[Command],
+) -> Result<CommandFamily, ParseError> {
+    let mut command_sets = Vec::new();
+    for t in tests.iter() {
+        for matrix in expand_matrix(&t.matrix)? {
+            // The values of the matrix are variables of the test
+            let mut test_vars = vars.vars.clone();
+            test_vars.extend(matrix.clone());
+            let test_vars = VarScope {
+                vars: test_vars,
+                captures: vars.captures.clone(),
+            };
+            command_sets.push(test_to_commandset(
+                shell,
+                &test_vars,
+                settings,
+                before_each,
+                t,
+                after_each,
+                matrix,
+            )?);
+        }
+    }
+
+    Ok(CommandFamily {
+        sets: command_sets,
+        processing_kind,
+        fail_fast: false,
+    })
+}
+
+fn test_to_commandset(
+    shell: &Shell,
+    vars: &VarScope,
+    settings: &ProcessSettings,
+    before_each: &[Command],
+    t: &Test,
+    after_each: &[Command],
+    matrix: Variables,
+) -> Result<CommandSet, ParseError> {
+    let mut settings = inherit_settings(
+        settings,
+        vars,
+        &t.env,
+        t.clear_env,
+        &t.cwd,
+        &t.stdin,
+        t.grace_period,
+    )?;
+    settings.timeout = t.timeout.or(settings.timeout);
+    // The hooks of the module run like a setup and teardown of every test
+    let mut test_vars = vars.clone();
+    let setup = test_vars.commands(
+        shell,
+        &settings,
+        before_each.iter().chain(t.setup.iter().flatten()),
+    )?;
+    let commands = test_vars.commands(shell, &settings, t.commands.iter())?;
+    let teardown = test_vars.commands(
+        shell,
+        &settings,
+        t.teardown.iter().flatten().chain(after_each.iter()),
+    )?;
+    Ok(CommandSet {
+        name: matrix_test_name(vars.substitute_opt(&t.name)?, &t.name, &matrix),
+        description: vars.substitute_opt(&t.description)?,
+        set_type: CommandSetType::Test,
+        setup,
+        commands,
+        teardown,
+        processing_kind: ProcessingKind::Serial,
+        tags: t.tags.clone().unwrap_or_default(),
+        matrix,
+        skip: match t.disabled {
+            Some(true) => Some("Disabled".to_string()),
+            _ => None,
+        },
+        budget: t.budget,
+        allow_failure: t.allow_failure.unwrap_or(false),
+        max_duration: t.max_duration,
+        warn_duration: duration_warning(t.max_duration, t.warn_duration)?,
+    })
+}
+
+// The values of every combination of a matrix, a test without a matrix has
+// a single one without values
+fn expand_matrix(matrix: &Option<MatrixConfig>) -> Result<Vec<Variables>, ParseError> {
+    let matrix = match matrix {
+        Some(matrix) => matrix,
+        None => return Ok(vec![Variables::new()]),
+    };
+    let error = |description: String| ParseError {
+        description,
+        line_col: None,
+    };
+    if matrix.params.is_empty() && matrix.include.is_none() {
+        return Err(error("The matrix has no parameters".to_string()));
+    }
+    let names = matrix.params.keys().chain(
+        matrix
+            .include
+            .iter()
+            .flatten()
+            .flat_map(|entry| entry.keys()),
+    );
+    for name in names {
+        if !is_valid_name(name) {
+            return Err(error(format!(
+                "Invalid matrix parameter '{}', use letters, digits and '_'",
+                name
+            )));
+        }
+    }
+
+    let mut combinations = if matrix.params.is_empty() {
+        Vec::new()
+    } else {
+        vec![Variables::new()]
+    };
+    for (name, values) in matrix.params.iter() {
+        if values.is_empty() {
+            return Err(error(format!("Matrix parameter '{}' has no values", name)));
+        }
+        combinations = combinations
+            .iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.insert(name.clone(), value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    let has_values = |combination: &Variables, entry: &Variables| {
+        entry
+            .iter()
+            .all(|(name, value)| combination.get(name) == Some(value))
+    };
+    for entry in matrix.exclude.iter().flatten() {
+        if let Some(name) = entry.keys().find(|name| !matrix.params.contains_key(*name)) {
+            return Err(error(format!(
+                "Unknown matrix parameter '{}' in exclude",
+                name
+            )));
+        }
+        combinations.retain(|combination| !has_values(combination, entry));
+    }
+
+    // An include entry adds its other values to the combinations with its
+    // values of the matrix parameters, or else is a combination of its own
+    let generated = combinations.len();
+    for entry in matrix.include.iter().flatten() {
+        let (params, others): (Variables, Variables) = entry
+            .clone()
+            .into_iter()
+            .partition(|(name, _)| matrix.params.contains_key(name));
+        let mut matched = false;
+        for combination in combinations[..generated].iter_mut() {
+            if has_values(combination, &params) {
+                combination.extend(others.clone());
+                matched = true;
+            }
+        }
+        if !matched {
+            combinations.push(entry.clone());
+        }
+    }
+    Ok(combinations)
+}
+
+// Like "upload (format=csv, region=eu)", without the values the name already
+// uses, so that every generated test has its own name
+fn matrix_test_name(
+    name: Option<String>,
+    template: &Option<String>,
+    matrix: &Variables,
+) -> Option<String> {
+    let uses = |key: &str| {
+        template
+            .as_ref()
+            .is_some_and(|template| template.contains(&format!("${{{}}}", key)))
+    };
+    let values = matrix
+        .iter()
+        .filter(|(key, _)| !uses(key))
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if values.is_empty() {
+        return name;
+    }
+    Some(match name {
+        Some(name) => format!("{} ({})", name, values),
+        None => values,
+    })
+}
+
+fn command_to_execommand(
+    shell: &Shell,
+    vars: &VarScope,
+    settings: &ProcessSettings,
+    cmd: &Command,
+) -> Result<ExecutableCommand, ParseError> {
+    let settings = inherit_settings(
+        settings,
+        vars,
+        &cmd.env,
+        cmd.clear_env,
+        &cmd.cwd,
+        &cmd.stdin,
+        cmd.grace_period,
+    )?;
+    Ok(ExecutableCommand {
+        name: vars.substitute_opt(&cmd.name)?,
+        description: vars.substitute_opt(&cmd.description)?,
+        timeout: cmd.timeout.or(settings.timeout),
+        grace_period: settings.grace_period,
+        shell: shell.clone(),
+        cmd: vars.substitute(&cmd.command)?,
+        env: settings.env,
+        clear_env: settings.clear_env,
+        cwd: settings.cwd,
+        stdin: settings.stdin,
+        expect: match &cmd.expect {
+            Some(expect) => expect_to_expectations(expect)?,
+            None => Expectations::default(),
+        },
+        capture: match &cmd.capture {
+            Some(capture) => Some(capture_config_to_capture(capture)?),
+            None => None,
+        },
+        retry: command_retry(cmd)?,
+        continue_on_error: cmd.continue_on_error.unwrap_or(false),
+        allow_failure: cmd.allow_failure.unwrap_or(false),
+        max_duration: cmd.max_duration,
+        warn_duration: duration_warning(cmd.max_duration, cmd.warn_duration)?,
+    })
+}
+
+// A warning only makes sense before the command or test fails as slow
+fn duration_warning(max: Option<u64>, warn: Option<u64>) -> Result<Option<u64>, ParseError> {
+    match (max, warn) {
+        (Some(max), Some(warn)) if warn >= max => Err(ParseError {
+            description: format!(
+                "warn_duration ({}ms) must be less than max_duration ({}ms)",
+                warn, max
+            ),
+            line_col: None,
+        }),
+        _ => Ok(warn),
+    }
+}
+
+pub const DEFAULT_RETRY_DELAY_MS: u64 = 500;
+
+fn command_retry(cmd: &Command) -> Result<Option<Retry>, ParseError> {
+    let error = |description: String| ParseError {
+        description,
+        line_col: None,
+    };
+    if cmd.retries.is_none() && cmd.until.is_none() {
+        if cmd.retry_delay.is_some() || cmd.backoff.is_some() {
+            return Err(error(format!(
+                "Command '{}' sets retry_delay or backoff without retries or until",
+                cmd.command
+            )));
+        }
+        return Ok(None);
+    }
+    let backoff = cmd.backoff.unwrap_or(1.0);
+    if backoff.is_nan() || backoff < 1.0 {
+        return Err(error(format!(
+            "Command '{}' has a backoff of {}, it must be at least 1",
+            cmd.command, backoff
+        )));
+    }
+    Ok(Some(Retry {
+        retries: cmd.retries,
+        delay: cmd.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY_MS),
+        backoff,
+        until: cmd.until,
+    }))
+}
+
+pub const DEFAULT_STARTUP_TIMEOUT_MS: u64 = 30000;
+pub const DEFAULT_PROBE_INTERVAL_MS: u64 = 100;
+
+fn service_config_to_service(
+    shell: &Shell,
+    vars: &VarScope,
+    settings: &ProcessSettings,
+    config: &ServiceConfig,
+) -> Result<Service, ParseError> {
+    let error = |description: String| ParseError {
+        description,
+        line_col: None,
+    };
+    let settings = inherit_settings(
+        settings,
+        vars,
+        &config.env,
+        config.clear_env,
+        &config.cwd,
+        &config.stdin,
+        config.grace_period,
+    )?;
+    let name = vars.substitute(&config.name)?;
+    let command = ExecutableCommand {
+        name: Some(name.clone()),
+        grace_period: settings.grace_period,
+        shell: shell.clone(),
+        cmd: vars.substitute(&config.command)?,
+        env: settings.env.clone(),
+        clear_env: settings.clear_env,
+        cwd: settings.cwd.clone(),
+        stdin: settings.stdin,
+        ..Default::default()
+    };
+
+    let ready = match &config.ready {
+        None => None,
+        Some(ready) => {
+            let probe = match (&ready.tcp, &ready.http, &ready.log, &ready.command) {
+                (Some(address), None, None, None) => {
+                    let address = vars.substitute(address)?;
+                    if !address.contains(':') {
+                        return Err(error(format!(
+                            "Service '{}' tcp probe needs a host:port address, got '{}'",
+                            name, address
+                        )));
+                    }
+                    ReadinessProbe::Tcp(address)
+                }
+                (None, Some(url), None, None) => {
+                    let url = vars.substitute(url)?;
+                    if !url.starts_with("http://") {
+                        return Err(error(format!(
+                            "Service '{}' http probe needs an http:// url, got '{}'",
+                            name, url
+                        )));
+                    }
+                    ReadinessProbe::Http(url)
+                }
+                (None, None, Some(pattern), None) => {
+                    Regex::new(pattern).map_err(|e| {
+                        error(format!("Invalid regex in service '{}': {}", name, e))
+                    })?;
+                    ReadinessProbe::Log(pattern.clone())
+                }
+                (None, None, None, Some(cmd)) => {
+                    ReadinessProbe::Command(Box::new(ExecutableCommand {
+                        name: Some(format!("{} ready", name)),
+                        timeout: Some(config.startup_timeout.unwrap_or(DEFAULT_STARTUP_TIMEOUT_MS)),
+                        shell: shell.clone(),
+                        cmd: vars.substitute(cmd)?,
+                        env: settings.env,
+                        clear_env: settings.clear_env,
+                        cwd: settings.cwd,
+                        ..Default::default()
+                    }))
+                }
+                _ => {
+                    return Err(error(format!(
+                        "Service '{}' needs exactly one of tcp, http, log or command in ready",
+                        name
+                    )))
+                }
+            };
+            Some(probe)
+        }
+    };
+
+    Ok(Service {
+        name,
+        command,
+        ready,
+        startup_timeout: config.startup_timeout.unwrap_or(DEFAULT_STARTUP_TIMEOUT_MS),
+        probe_interval: config
+            .ready
+            .as_ref()
+            .and_then(|r| r.interval)
+            .unwrap_or(DEFAULT_PROBE_INTERVAL_MS),
+    })
+}
+
+fn capture_config_to_capture(config: &CaptureConfig) -> Result<Capture, ParseError> {
+    let error = |description: String| ParseError {
+        description,
+        line_col: None,
+    };
+    let capture = match config {
+        CaptureConfig::Name(name) => Capture {
+            name: name.clone(),
+            trim: true,
+            extract: CaptureExtract::Stdout,
+        },
+        CaptureConfig::Options(options) => Capture {
+            name: options.name.clone(),
+            trim: options.trim.unwrap_or(true),
+            extract: match (&options.regex, &options.json) {
+                (Some(_), Some(_)) => {
+                    return Err(error(format!(
+                        "Capture '{}' can't have both a regex and a json path",
+                        options.name
+                    )))
+                }
+                (Some(pattern), None) => {
+                    Regex::new(pattern)
+                        .map_err(|e| error(format!("Invalid regex in capture: {}", e)))?;
+                    CaptureExtract::Regex(pattern.clone())
+                }
+                (None, Some(path)) => CaptureExtract::Json {
+                    path: path.clone(),
+                    pointer: json_path_to_pointer(path)
+                        .map_err(|e| error(format!("Invalid path in capture: {}", e)))?,
+                },
+                (None, None) => CaptureExtract::Stdout,
+            },
+        },
+    };
+    if !is_valid_name(&capture.name) {
+        return Err(error(format!(
+            "Invalid capture name '{}', use letters, digits and '_'",
+            capture.name
+        )));
+    }
+    Ok(capture)
+}
+
+fn expect_to_expectations(expect: &Expect) -> Result<Expectations, ParseError> {
+    fn matchers(
+        contains: &Option<String>,
+        equals: &Option<String>,
+        matches: &Option<String>,
+        empty: &Option<bool>,
+    ) -> Result<Vec<OutputMatcher>, ParseError> {
+        if let Some(pattern) = matches {
+            Regex::new(pattern).map_err(|e| ParseError {
+                description: format!("Invalid regex in expect: {}", e),
+                line_col: None,
+            })?;
+        }
+        Ok(vec![
+            contains.clone().map(OutputMatcher::Contains),
+            equals.clone().map(OutputMatcher::Equals),
+            matches.clone().map(OutputMatcher::Matches),
+            empty.map(OutputMatcher::Empty),
+        ]
+        .into_iter()
+        .flatten()
+        .collect())
+    }
+
+    Ok(Expectations {
+        exit_codes: match &expect.exit_code {
+            Some(ExitCodes::One(code)) => vec![*code],
+            Some(ExitCodes::Many(codes)) => codes.clone(),
+            None => vec![0],
+        },
+        stdout: matchers(
+            &expect.stdout_contains,
+            &expect.stdout_equals,
+            &expect.stdout_matches,
+            &expect.stdout_empty,
+        )?,
+        stderr: matchers(
+            &expect.stderr_contains,
+            &expect.stderr_equals,
+            &expect.stderr_matches,
+            &expect.stderr_empty,
+        )?,
+        json: match &expect.json {
+            Some(json) => json_matchers(json)?,
+            None => vec![],
+        },
+    })
+}
+
+// Each check of a json expectation becomes its own matcher
+fn json_matchers(json: &[JsonExpect]) -> Result<Vec<JsonMatcher>, ParseError> {
+    let mut matchers = vec![];
+    for j in json {
+        let pointer = json_path_to_pointer(&j.path).map_err(|e| ParseError {
+            description: format!("Invalid path in json expect: {}", e),
+            line_col: None,
+        })?;
+        let value_type = match j.value_type.as_deref() {
+            Some(t) => Some(json_type(t).ok_or_else(|| ParseError {
+                description: format!("Invalid type in json expect: '{}', expected one of null, bool, number, string, array or object", t),
+                line_col: None,
+            })?),
+            None => None,
+        };
+        let checks: Vec<JsonCheck> = vec![
+            j.equals.clone().map(JsonCheck::Equals),
+            j.exists.map(JsonCheck::Exists),
+            value_type.map(JsonCheck::Type),
+            j.length.map(JsonCheck::Length),
+            j.gt.map(JsonCheck::GreaterThan),
+            j.gte.map(JsonCheck::GreaterOrEqual),
+            j.lt.map(JsonCheck::LessThan),
+            j.lte.map(JsonCheck::LessOrEqual),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if checks.is_empty() {
+            return Err(ParseError {
+                description: format!("Nothing to check in json expect for path '{}'", j.path),
+                line_col: None,
+            });
+        }
+        matchers.extend(checks.into_iter().map(|check| JsonMatcher {
+            path: j.path.clone(),
+            pointer: pointer.clone(),
+            check,
+        }));
+    }
+    Ok(matchers)
+}
+
+fn json_type(name: &str) -> Option<JsonType> {
+    match name {
+        "null" => Some(JsonType::Null),
+        "bool" | "boolean" => Some(JsonType::Bool),
+        "number" => Some(JsonType::Number),
+        "string" => Some(JsonType::String),
+        "array" => Some(JsonType::Array),
+        "object" => Some(JsonType::Object),
+        _ => None,
     }
 }
 
@@ -272,37 +1427,103 @@ fn t_map_module() {
     let res = testmodule_to_processingmodel(TestModule {
         version: "3".to_string(),
         shell: None,
-        setup: Some(vec!(
-            Command {
+        parallel: None,
+        max_parallel: None,
+        vars: None,
+        env: None,
+        clear_env: None,
+        cwd: None,
+        stdin: None,
+        grace_period: None,
+        timeout: None,
+        setup: Some(vec![Command {
+            name: Option::None,
+            description: Option::None,
+            timeout: None,
+            command: "abc".to_string(),
+            env: None,
+            clear_env: None,
+            cwd: None,
+            stdin: None,
+            grace_period: None,
+            retries: None,
+            retry_delay: None,
+            backoff: None,
+            until: None,
+            continue_on_error: None,
+            allow_failure: None,
+            max_duration: None,
+            warn_duration: None,
+            expect: None,
+            capture: None,
+        }]),
+        services: None,
+        before_each: None,
+        after_each: None,
+        tests: vec![Test {
+            name: Option::None,
+            description: Option::None,
+            tags: None,
+            disabled: None,
+            env: None,
+            clear_env: None,
+            cwd: None,
+            stdin: None,
+            grace_period: None,
+            timeout: None,
+            budget: None,
+            allow_failure: None,
+            max_duration: None,
+            warn_duration: None,
+            matrix: None,
+            setup: None,
+            teardown: None,
+            commands: vec![Command {
                 name: Option::None,
                 description: Option::None,
                 timeout: None,
-                command: "abc".to_string(),
-            }
-        )),
-        tests: vec!(
-            Test {
-                name: Option::None,
-                description: Option::None,
-                commands: vec!(
-                    Command {
-                        name: Option::None,
-                        description: Option::None,
-                        timeout: None,
-                        command: "def".to_string(),
-                    }
-                )
-            }
-        ),
-        teardown: Some(vec!(
-            Command {
-                name: Option::None,
-                description: Option::None,
-                timeout: None,
-                command: "ghi".to_string(),
-            }
-        )),
-    });
+                command: "def".to_string(),
+                env: None,
+                clear_env: None,
+                cwd: None,
+                stdin: None,
+                grace_period: None,
+                retries: None,
+                retry_delay: None,
+                backoff: None,
+                until: None,
+                continue_on_error: None,
+                allow_failure: None,
+                max_duration: None,
+                warn_duration: None,
+                expect: None,
+                capture: None,
+            }],
+        }],
+        teardown: Some(vec![Command {
+            name: Option::None,
+            description: Option::None,
+            timeout: None,
+            command: "ghi".to_string(),
+            env: None,
+            clear_env: None,
+            cwd: None,
+            stdin: None,
+            grace_period: None,
+            retries: None,
+            retry_delay: None,
+            backoff: None,
+            until: None,
+            continue_on_error: None,
+            allow_failure: None,
+            max_duration: None,
+            warn_duration: None,
+            expect: None,
+            capture: None,
+        }]),
+        teardown_policy: None,
+    })
+    .unwrap();
 
     assert_eq!(res.setup.set_type, CommandSetType::Setup);
     assert_eq!(res.setup.commands[0].name, Option::None);
@@ -321,4 +1542,1001 @@ fn t_map_module() {
     assert_eq!(res.teardown.commands[0].description, Option::None);
     assert_eq!(res.teardown.commands[0].cmd, "ghi");
     assert_eq!(res.teardown.processing_kind, ProcessingKind::Serial);
+    assert_eq!(res.teardown_policy, TeardownPolicy::Always);
+}
+
+#[test]
+fn t_map_teardown_policy() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+        teardown_policy = "on_success"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(res.teardown_policy, TeardownPolicy::OnSuccess);
+
+    let err = parse_toml(
+        r#"
+        version = "3"
+        teardown_policy = "sometimes"
+        test = []
+    "#
+        .to_string(),
+    )
+    .expect_err("Should have failed");
+
+    assert!(err.description.contains("unknown variant `sometimes`"));
+}
+
+#[test]
+fn t_map_parallel() {
+    let parse =
+        |s: &str| testmodule_to_processingmodel(parse_toml(s.to_string()).unwrap()).unwrap();
+    let tests = r#"
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+    "#;
+
+    let serial = parse(&format!("version = \"3\"\n{}", tests));
+    assert_eq!(serial.tests.processing_kind, ProcessingKind::Serial);
+
+    let parallel = parse(&format!("version = \"3\"\nparallel = true\n{}", tests));
+    assert_eq!(
+        parallel.tests.processing_kind,
+        ProcessingKind::Parallel(default_max_parallel())
+    );
+
+    let bounded = parse(&format!("version = \"3\"\nmax_parallel = 8\n{}", tests));
+    assert_eq!(bounded.tests.processing_kind, ProcessingKind::Parallel(8));
+
+    let disabled = parse(&format!(
+        "version = \"3\"\nparallel = false\nmax_parallel = 8\n{}",
+        tests
+    ));
+    assert_eq!(disabled.tests.processing_kind, ProcessingKind::Serial);
+
+    // Only the tests run in parallel, setup and teardown stay in order
+    assert_eq!(bounded.setup.processing_kind, ProcessingKind::Serial);
+}
+
+#[test]
+fn t_map_expect() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+        [test.command.expect]
+        exit_code = [0, 2]
+        stdout_contains = "hello"
+        stderr_empty = true
+        [[test.command]]
+        cmd = "def"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.tests.sets[0].commands[0].expect,
+        Expectations {
+            exit_codes: vec!(0, 2),
+            stdout: vec!(OutputMatcher::Contains("hello".to_string())),
+            stderr: vec!(OutputMatcher::Empty(true)),
+            json: vec!(),
+        }
+    );
+    assert_eq!(
+        res.tests.sets[0].commands[1].expect,
+        Expectations::default()
+    );
+
+    let err = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+        expect = { exit_code = 1, stdout_matches = "(" }
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .expect_err("Should have failed");
+
+    assert!(err.description.starts_with("Invalid regex in expect"));
+}
+
+#[test]
+fn t_map_expect_json() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "curl -s localhost:8080/health"
+        [[test.command.expect.json]]
+        path = "$.status"
+        equals = "UP"
+        [[test.command.expect.json]]
+        path = "$.checks"
+        type = "array"
+        length = 2
+        [[test.command.expect.json]]
+        path = "/uptime"
+        gt = 0
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let matcher = |path: &str, pointer: &str, check| JsonMatcher {
+        path: path.to_string(),
+        pointer: pointer.to_string(),
+        check,
+    };
+    assert_eq!(
+        res.tests.sets[0].commands[0].expect.json,
+        vec!(
+            matcher(
+                "$.status",
+                "/status",
+                JsonCheck::Equals(serde_json::json!("UP"))
+            ),
+            matcher("$.checks", "/checks", JsonCheck::Type(JsonType::Array)),
+            matcher("$.checks", "/checks", JsonCheck::Length(2)),
+            matcher("/uptime", "/uptime", JsonCheck::GreaterThan(0.0)),
+        )
+    );
+
+    let err = |json: &str| {
+        testmodule_to_processingmodel(
+            parse_toml(format!(
+                r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+        expect = {{ json = [{}] }}
+    "#,
+                json
+            ))
+            .unwrap(),
+        )
+        .expect_err("Should have failed")
+        .description
+    };
+
+    assert!(
+        err(r#"{ path = "$.items[*]", exists = true }"#).starts_with("Invalid path in json expect")
+    );
+    assert!(err(r#"{ path = "$.a", type = "list" }"#).starts_with("Invalid type in json expect"));
+    assert!(err(r#"{ path = "$.a" }"#).starts_with("Nothing to check in json expect"));
+}
+
+#[test]
+fn t_map_capture() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+        capture = "all"
+        [[test.command]]
+        cmd = "def"
+        capture = { name = "id", regex = "id=(\\d+)", trim = false }
+        [[test.command]]
+        cmd = "ghi"
+        capture = { name = "token", json = "$.token" }
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let captures: Vec<_> = res.tests.sets[0]
+        .commands
+        .iter()
+        .map(|c| c.capture.clone().unwrap())
+        .collect();
+    assert_eq!(
+        captures,
+        vec!(
+            Capture {
+                name: "all".to_string(),
+                trim: true,
+                extract: CaptureExtract::Stdout
+            },
+            Capture {
+                name: "id".to_string(),
+                trim: false,
+                extract: CaptureExtract::Regex("id=(\\d+)".to_string())
+            },
+            Capture {
+                name: "token".to_string(),
+                trim: true,
+                extract: CaptureExtract::Json {
+                    path: "$.token".to_string(),
+                    pointer: "/token".to_string()
+                },
+            },
+        )
+    );
+
+    let err = |capture: &str| {
+        testmodule_to_processingmodel(
+            parse_toml(format!(
+                r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+        capture = {}
+    "#,
+                capture
+            ))
+            .unwrap(),
+        )
+        .expect_err("Should have failed")
+        .description
+    };
+
+    assert!(err(r#""my-var""#).starts_with("Invalid capture name"));
+    assert!(err(r#"{ name = "a", regex = "(" }"#).starts_with("Invalid regex in capture"));
+    assert!(err(r#"{ name = "a", regex = "a", json = "$.a" }"#)
+        .contains("both a regex and a json path"));
+}
+
+#[test]
+fn t_map_capture_scope() {
+    let map = |tests: &str| {
+        testmodule_to_processingmodel(
+            parse_toml(format!(
+                r#"
+        version = "3"
+
+        [[setup]]
+        cmd = "login"
+        capture = "token"
+
+        [[before_each]]
+        cmd = "create"
+        capture = "id"
+
+        {}
+    "#,
+                tests
+            ))
+            .unwrap(),
+        )
+    };
+
+    let res = map(r#"
+        [[test]]
+        [[test.command]]
+        cmd = "get ${id} ${token}"
+        capture = "item"
+        [[test.command]]
+        cmd = "check ${item}"
+        [[test.teardown]]
+        cmd = "delete ${item}"
+    "#)
+    .unwrap();
+    let test = &res.tests.sets[0];
+    assert_eq!(test.commands[1].cmd, "check ${item}");
+    assert_eq!(test.teardown[0].cmd, "delete ${item}");
+
+    // Captured in another test
+    let err = map(r#"
+        [[test]]
+        [[test.command]]
+        cmd = "get"
+        capture = "item"
+        [[test]]
+        [[test.command]]
+        cmd = "check ${item}"
+    "#)
+    .expect_err("Should have failed");
+    assert_eq!(err.description, "Undefined variable 'item'");
+
+    // Used before the command that captures it
+    let err = map(r#"
+        [[test]]
+        [[test.command]]
+        cmd = "check ${item}"
+        [[test.command]]
+        cmd = "get"
+        capture = "item"
+    "#)
+    .expect_err("Should have failed");
+    assert_eq!(err.description, "Undefined variable 'item'");
+}
+
+#[test]
+fn t_map_vars() {
+    let config = r#"
+        version = "3"
+
+        [vars]
+        host = "localhost:8080"
+        dir = "${env:FCHECK_UNDEFINED_VAR:-/tmp}/data"
+
+        [shell]
+        path = "/bin/bash"
+        args = ["-c"]
+
+        [[setup]]
+        cmd = "login"
+        capture = "token"
+
+        [[test]]
+        name = "get ${host}"
+        description = "items of ${host}"
+        [[test.command]]
+        name = "curl ${host}"
+        description = "in ${dir}"
+        cmd = "curl ${host}/items -H ${token} > ${dir}/out.txt; echo $${HOME}"
+    "#;
+
+    let res = testmodule_to_processingmodel(parse_toml(config.to_string()).unwrap()).unwrap();
+    let set = &res.tests.sets[0];
+    assert_eq!(set.name, Some("get localhost:8080".to_string()));
+    assert_eq!(set.description, Some("items of localhost:8080".to_string()));
+    assert_eq!(
+        set.commands[0].name,
+        Some("curl localhost:8080".to_string())
+    );
+    assert_eq!(
+        set.commands[0].description,
+        Some("in /tmp/data".to_string())
+    );
+    assert_eq!(
+        set.commands[0].cmd,
+        "curl localhost:8080/items -H ${token} > /tmp/data/out.txt; echo ${HOME}"
+    );
+
+    let mut overrides = Variables::new();
+    overrides.insert("host".to_string(), "example.com".to_string());
+    let res = prepare_file(FileType::Toml, config.to_string(), &overrides).unwrap();
+    assert_eq!(res.tests.sets[0].name, Some("get example.com".to_string()));
+
+    let err = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "echo ${port}"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .expect_err("Should have failed");
+    assert_eq!(err.description, "Undefined variable 'port'");
+}
+
+#[test]
+fn t_map_env_cwd_stdin() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+        cwd = "/app"
+        stdin = "module input"
+        grace_period = 500
+
+        [vars]
+        level = "debug"
+
+        [env]
+        LOG_LEVEL = "${level}"
+        MODE = "module"
+
+        [[setup]]
+        cmd = "abc"
+
+        [[test]]
+        cwd = "service"
+        clear_env = true
+        env = { MODE = "test" }
+        [[test.command]]
+        cmd = "def"
+        [[test.command]]
+        cmd = "ghi"
+        cwd = "/tmp"
+        clear_env = false
+        grace_period = 0
+        stdin = { file = "input.json" }
+        env = { EXTRA = "1" }
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let env = |pairs: &[(&str, &str)]| {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    let setup = &res.setup.commands[0];
+    assert_eq!(
+        setup.env,
+        env(&[("LOG_LEVEL", "debug"), ("MODE", "module")])
+    );
+    assert_eq!(setup.cwd, Some("/app".to_string()));
+    assert_eq!(setup.stdin, Some(Stdin::Text("module input".to_string())));
+    assert!(!setup.clear_env);
+    assert_eq!(setup.grace_period, Some(500));
+
+    let first = &res.tests.sets[0].commands[0];
+    assert_eq!(first.env, env(&[("LOG_LEVEL", "debug"), ("MODE", "test")]));
+    assert_eq!(first.cwd, Some("/app/service".to_string()));
+    assert!(first.clear_env);
+
+    let second = &res.tests.sets[0].commands[1];
+    assert_eq!(
+        second.env,
+        env(&[("EXTRA", "1"), ("LOG_LEVEL", "debug"), ("MODE", "test")])
+    );
+    assert_eq!(second.cwd, Some("/tmp".to_string()));
+    assert_eq!(second.stdin, Some(Stdin::File("input.json".to_string())));
+    assert!(!second.clear_env);
+    assert_eq!(second.grace_period, Some(0));
+}
+
+#[test]
+fn t_map_services() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+        cwd = "/srv"
+
+        [vars]
+        port = "8080"
+
+        [[service]]
+        name = "api"
+        cmd = "./api --port ${port}"
+        cwd = "api"
+        startup_timeout = 5000
+        ready = { http = "http://localhost:${port}/health", interval = 50 }
+
+        [[service]]
+        name = "worker"
+        cmd = "./worker"
+        ready = { log = "started \\d+ workers" }
+
+        [[service]]
+        name = "db"
+        cmd = "./db"
+
+        [[test]]
+        [[test.command]]
+        cmd = "curl localhost:${port}"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let api = &res.services[0];
+    assert_eq!(api.name, "api");
+    assert_eq!(api.command.cmd, "./api --port 8080");
+    assert_eq!(api.command.cwd, Some("/srv/api".to_string()));
+    assert_eq!(
+        api.ready,
+        Some(ReadinessProbe::Http(
+            "http://localhost:8080/health".to_string()
+        ))
+    );
+    assert_eq!(api.startup_timeout, 5000);
+    assert_eq!(api.probe_interval, 50);
+
+    assert_eq!(
+        res.services[1].ready,
+        Some(ReadinessProbe::Log("started \\d+ workers".to_string()))
+    );
+    assert_eq!(res.services[1].startup_timeout, DEFAULT_STARTUP_TIMEOUT_MS);
+    assert_eq!(res.services[2].ready, None);
+
+    let err = |ready: &str| {
+        testmodule_to_processingmodel(
+            parse_toml(format!(
+                r#"
+        version = "3"
+
+        [[service]]
+        name = "api"
+        cmd = "./api"
+        ready = {}
+
+        [[test]]
+        [[test.command]]
+        cmd = "true"
+    "#,
+                ready
+            ))
+            .unwrap(),
+        )
+        .expect_err("Should have failed")
+        .description
+    };
+
+    assert_eq!(
+        err(r#"{ tcp = "localhost" }"#),
+        "Service 'api' tcp probe needs a host:port address, got 'localhost'"
+    );
+    assert_eq!(
+        err(r#"{ http = "https://localhost" }"#),
+        "Service 'api' http probe needs an http:// url, got 'https://localhost'"
+    );
+    assert_eq!(
+        err(r#"{ tcp = "localhost:80", log = "up" }"#),
+        "Service 'api' needs exactly one of tcp, http, log or command in ready"
+    );
+    assert!(err(r#"{ log = "(" }"#).starts_with("Invalid regex in service 'api'"));
+}
+
+#[test]
+fn t_map_retry() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "kafka-read"
+        retries = 5
+        retry_delay = 100
+        backoff = 2.0
+
+        [[test.command]]
+        cmd = "curl localhost"
+        until = 10000
+
+        [[test.command]]
+        cmd = "once"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let commands = &res.tests.sets[0].commands;
+    assert_eq!(
+        commands[0].retry,
+        Some(Retry {
+            retries: Some(5),
+            delay: 100,
+            backoff: 2.0,
+            until: None
+        })
+    );
+    assert_eq!(
+        commands[1].retry,
+        Some(Retry {
+            retries: None,
+            delay: DEFAULT_RETRY_DELAY_MS,
+            backoff: 1.0,
+            until: Some(10000)
+        })
+    );
+    assert_eq!(commands[2].retry, None);
+
+    let err = |settings: &str| {
+        testmodule_to_processingmodel(
+            parse_toml(format!(
+                r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+        {}
+    "#,
+                settings
+            ))
+            .unwrap(),
+        )
+        .expect_err("Should have failed")
+        .description
+    };
+
+    assert_eq!(
+        err("retry_delay = 100"),
+        "Command 'abc' sets retry_delay or backoff without retries or until"
+    );
+    assert_eq!(
+        err("retries = 2\nbackoff = 0.5"),
+        "Command 'abc' has a backoff of 0.5, it must be at least 1"
+    );
+}
+
+#[test]
+fn t_map_timeouts() {
+    let mut res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+        timeout = 1000
+
+        [[setup]]
+        cmd = "setup"
+
+        [[service]]
+        name = "server"
+        cmd = "./server"
+
+        [[test]]
+        timeout = 500
+        budget = 10000
+        [[test.command]]
+        cmd = "inherits"
+        [[test.command]]
+        cmd = "no timeout"
+        timeout = 0
+
+        [[test]]
+        [[test.command]]
+        cmd = "module"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(res.setup.commands[0].timeout, Some(1000));
+    assert_eq!(res.services[0].command.timeout, None);
+    let sets = &res.tests.sets;
+    assert_eq!(sets[0].budget, Some(10000));
+    assert_eq!(sets[0].commands[0].timeout, Some(500));
+    assert_eq!(sets[0].commands[1].timeout, Some(0));
+    assert_eq!(
+        (sets[1].budget, sets[1].commands[0].timeout),
+        (None, Some(1000))
+    );
+
+    // The default from the command line is only for commands without a timeout
+    res.tests.sets[1].commands[0].timeout = None;
+    res.set_default_timeout(3000);
+    let sets = &res.tests.sets;
+    assert_eq!(sets[0].commands[1].timeout, Some(0));
+    assert_eq!(sets[1].commands[0].timeout, Some(3000));
+    assert_eq!(res.services[0].command.timeout, None);
+}
+
+#[test]
+fn t_map_durations() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        name = "health"
+        max_duration = 2000
+        warn_duration = 1000
+        [[test.command]]
+        cmd = "curl localhost/health"
+        max_duration = 500
+        warn_duration = 200
+
+        [[test.command]]
+        cmd = "curl localhost/ready"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let set = &res.tests.sets[0];
+    assert_eq!(
+        (set.max_duration, set.warn_duration),
+        (Some(2000), Some(1000))
+    );
+    let commands = &set.commands;
+    assert_eq!(
+        (commands[0].max_duration, commands[0].warn_duration),
+        (Some(500), Some(200))
+    );
+    // Not inherited, the limits of the test are for all of its commands
+    assert_eq!(
+        (commands[1].max_duration, commands[1].warn_duration),
+        (None, None)
+    );
+
+    let err = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        [[test.command]]
+        cmd = "abc"
+        max_duration = 500
+        warn_duration = 500
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .expect_err("Should have failed");
+    assert_eq!(
+        err.description,
+        "warn_duration (500ms) must be less than max_duration (500ms)"
+    );
+}
+
+#[test]
+fn t_map_failure_handling() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        allow_failure = true
+        [[test.command]]
+        cmd = "flaky"
+        [[test.command]]
+        cmd = "cleanup"
+        continue_on_error = true
+        [[test.command]]
+        cmd = "optional"
+        allow_failure = true
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let set = &res.tests.sets[0];
+    assert!(set.allow_failure);
+    assert!(!res.tests.fail_fast);
+    let flags: Vec<_> = set
+        .commands
+        .iter()
+        .map(|c| (c.continue_on_error, c.allow_failure))
+        .collect();
+    assert_eq!(flags, vec![(false, false), (true, false), (false, true)]);
+}
+
+#[test]
+fn t_map_test_hooks() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[before_each]]
+        cmd = "before"
+        [[after_each]]
+        cmd = "after"
+
+        [[test]]
+        env = { DB = "test" }
+        [[test.setup]]
+        cmd = "setup"
+        [[test.command]]
+        cmd = "check"
+        [[test.teardown]]
+        cmd = "teardown"
+
+        [[test]]
+        [[test.command]]
+        cmd = "plain"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let cmds = |commands: &Vec<ExecutableCommand>| -> Vec<String> {
+        commands.iter().map(|c| c.cmd.clone()).collect()
+    };
+    let sets = &res.tests.sets;
+    assert_eq!(cmds(&sets[0].setup), vec!["before", "setup"]);
+    assert_eq!(cmds(&sets[0].teardown), vec!["teardown", "after"]);
+    assert_eq!(
+        sets[0].setup[1].env.get("DB").map(|v| v.as_str()),
+        Some("test")
+    );
+    assert_eq!(cmds(&sets[1].setup), vec!["before"]);
+    assert_eq!(cmds(&sets[1].commands), vec!["plain"]);
+    assert_eq!(cmds(&sets[1].teardown), vec!["after"]);
+}
+
+#[test]
+fn t_map_matrix() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        name = "convert"
+        [test.matrix]
+        format = ["csv", "json"]
+        region = ["eu", "us"]
+        exclude = [{ format = "csv", region = "us" }]
+        include = [
+            { format = "json", region = "us", compress = "gzip" },
+            { format = "xml", region = "eu" },
+        ]
+        [[test.command]]
+        cmd = "convert --to ${format} --region ${region}"
+
+        [[test]]
+        name = "upload ${target}"
+        matrix = { target = ["s3", "gcs"] }
+        [[test.command]]
+        cmd = "upload ${target}"
+
+        [[test]]
+        name = "sync ${target}"
+        matrix = { target = ["s3"], region = ["eu", "us"] }
+        [[test.command]]
+        cmd = "sync ${target} ${region}"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let names: Vec<_> = res
+        .tests
+        .sets
+        .iter()
+        .map(|s| s.name.clone().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "convert (format=csv, region=eu)",
+            "convert (format=json, region=eu)",
+            "convert (compress=gzip, format=json, region=us)",
+            "convert (format=xml, region=eu)",
+            "upload s3",
+            "upload gcs",
+            "sync s3 (region=eu)",
+            "sync s3 (region=us)",
+        ]
+    );
+    let sets = &res.tests.sets;
+    assert_eq!(sets[1].commands[0].cmd, "convert --to json --region eu");
+    assert_eq!(
+        sets[2].matrix.get("compress").map(|v| v.as_str()),
+        Some("gzip")
+    );
+    assert_eq!(sets[3].commands[0].cmd, "convert --to xml --region eu");
+    assert_eq!(sets[5].commands[0].cmd, "upload gcs");
+
+    let matrix = |matrix: &str| {
+        let config = format!(
+            "version = \"3\"\n[[test]]\nmatrix = {}\n[[test.command]]\ncmd = \"true\"\n",
+            matrix
+        );
+        testmodule_to_processingmodel(parse_toml(config).unwrap()).map(|_| ())
+    };
+    assert_eq!(
+        matrix("{ os = [] }").unwrap_err().description,
+        "Matrix parameter 'os' has no values"
+    );
+    assert_eq!(
+        matrix("{ os = [\"a\"], exclude = [{ arch = \"b\" }] }")
+            .unwrap_err()
+            .description,
+        "Unknown matrix parameter 'arch' in exclude"
+    );
+    assert_eq!(
+        matrix("{ \"o-s\" = [\"a\"] }").unwrap_err().description,
+        "Invalid matrix parameter 'o-s', use letters, digits and '_'"
+    );
+}
+
+#[test]
+fn t_map_tags_and_disabled() {
+    let res = testmodule_to_processingmodel(
+        parse_toml(
+            r#"
+        version = "3"
+
+        [[test]]
+        name = "api"
+        tags = ["smoke", "http"]
+        [[test.command]]
+        cmd = "true"
+
+        [[test]]
+        name = "slow"
+        disabled = true
+        [[test.command]]
+        cmd = "true"
+    "#
+            .to_string(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        res.tests.sets[0].tags,
+        vec!("smoke".to_string(), "http".to_string())
+    );
+    assert_eq!(res.tests.sets[0].skip, None);
+    assert_eq!(res.tests.sets[1].tags, Vec::<String>::new());
+    assert_eq!(res.tests.sets[1].skip, Some("Disabled".to_string()));
+}
+
+#[test]
+fn t_find_config_files() {
+    let dir = std::env::temp_dir().join(format!("fcheck-find-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    for path in [
+        "b.fcheck.toml",
+        "a/c.fcheck.dhall",
+        "a/notes.toml",
+        ".git/d.fcheck.toml",
+        "a/b/a.fcheck.toml",
+    ] {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+
+    let files = find_config_files(&dir).unwrap();
+    let names: Vec<_> = files
+        .iter()
+        .map(|f| f.strip_prefix(&dir).unwrap().to_str().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        names,
+        vec!("a/b/a.fcheck.toml", "a/c.fcheck.dhall", "b.fcheck.toml")
+    );
+
+    let _ = std::fs::remove_dir_all(&dir);
 }
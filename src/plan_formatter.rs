@@ -0,0 +1,568 @@
+use std::collections::BTreeMap;
+
+use serde_derive::Serialize;
+
+use crate::assertions::describe_expectations;
+use crate::model::{
+    Capture, CaptureExtract, CommandFamily, CommandSet, ExecutableCommand, ProcessingKind,
+    ProcessingModule, ReadinessProbe, Retry, Service, Shell, Stdin, TeardownPolicy, Variables,
+};
+use crate::output_formatter::to_json;
+use crate::processor::DEFAULT_GRACE_PERIOD_MS;
+
+// What a module will run, with every default filled in, in the order it runs.
+// Nothing is run to build it.
+
+#[derive(Serialize, Debug)]
+pub struct PlanOutput {
+    modules: Vec<ModulePlanOutput>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModulePlanOutput {
+    file: String,
+    #[serde(flatten)]
+    module: ModulePlan,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModulePlan {
+    shell: Vec<String>,
+    teardown_policy: String,
+    setup: SetPlan,
+    services: Vec<ServicePlan>,
+    tests: TestsPlan,
+    teardown: SetPlan,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TestsPlan {
+    // "serial" or "parallel"
+    processing: String,
+    max_parallel: Option<usize>,
+    fail_fast: bool,
+    sets: Vec<SetPlan>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SetPlan {
+    name: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    matrix: Variables,
+    skipped: Option<String>,
+    budget: Option<u64>,
+    allow_failure: bool,
+    max_duration: Option<u64>,
+    warn_duration: Option<u64>,
+    setup: Vec<CommandPlan>,
+    commands: Vec<CommandPlan>,
+    teardown: Vec<CommandPlan>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServicePlan {
+    name: String,
+    command: CommandPlan,
+    ready: Option<String>,
+    startup_timeout: u64,
+    probe_interval: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CommandPlan {
+    name: Option<String>,
+    description: Option<String>,
+    shell: Vec<String>,
+    cmd: String,
+    timeout: Option<u64>,
+    grace_period: u64,
+    env: BTreeMap<String, String>,
+    clear_env: bool,
+    cwd: Option<String>,
+    stdin: Option<String>,
+    expect: Vec<String>,
+    capture: Option<String>,
+    retry: Option<RetryPlan>,
+    continue_on_error: bool,
+    allow_failure: bool,
+    max_duration: Option<u64>,
+    warn_duration: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RetryPlan {
+    retries: Option<u32>,
+    delay: u64,
+    backoff: f64,
+    until: Option<u64>,
+}
+
+// A single config file is planned on its own, several under `modules`, like the report
+pub fn format_plan_json(modules: &[(String, ProcessingModule)]) -> String {
+    match modules {
+        [(_, module)] => to_json(&map_module(module)),
+        _ => to_json(&PlanOutput {
+            modules: modules
+                .iter()
+                .map(|(file, module)| ModulePlanOutput {
+                    file: file.clone(),
+                    module: map_module(module),
+                })
+                .collect(),
+        }),
+    }
+}
+
+pub fn format_plan_text(modules: &[(String, ProcessingModule)]) -> String {
+    modules
+        .iter()
+        .map(|(file, module)| format!("==> {}\n{}", file, module_text(module)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn map_module(module: &ProcessingModule) -> ModulePlan {
+    ModulePlan {
+        shell: shell_args(&module.shell),
+        teardown_policy: teardown_policy(&module.teardown_policy).to_string(),
+        setup: map_set(&module.setup),
+        services: module.services.iter().map(map_service).collect(),
+        tests: map_tests(&module.tests),
+        teardown: map_set(&module.teardown),
+    }
+}
+
+fn map_tests(tests: &CommandFamily) -> TestsPlan {
+    let (processing, max_parallel) = match tests.processing_kind {
+        ProcessingKind::Serial => ("serial", None),
+        ProcessingKind::Parallel(max) => ("parallel", Some(max)),
+    };
+    TestsPlan {
+        processing: processing.to_string(),
+        max_parallel,
+        fail_fast: tests.fail_fast,
+        sets: tests.sets.iter().map(map_set).collect(),
+    }
+}
+
+fn map_set(set: &CommandSet) -> SetPlan {
+    SetPlan {
+        name: set.name.clone(),
+        description: set.description.clone(),
+        tags: set.tags.clone(),
+        matrix: set.matrix.clone(),
+        skipped: set.skip.clone(),
+        budget: set.budget,
+        allow_failure: set.allow_failure,
+        max_duration: set.max_duration,
+        warn_duration: set.warn_duration,
+        setup: set.setup.iter().map(map_command).collect(),
+        commands: set.commands.iter().map(map_command).collect(),
+        teardown: set.teardown.iter().map(map_command).collect(),
+    }
+}
+
+fn map_service(service: &Service) -> ServicePlan {
+    ServicePlan {
+        name: service.name.clone(),
+        command: map_command(&service.command),
+        ready: service.ready.as_ref().map(readiness),
+        startup_timeout: service.startup_timeout,
+        probe_interval: service.probe_interval,
+    }
+}
+
+fn map_command(command: &ExecutableCommand) -> CommandPlan {
+    CommandPlan {
+        name: command.name.clone(),
+        description: command.description.clone(),
+        shell: shell_args(&command.shell),
+        cmd: command.cmd.clone(),
+        timeout: command.timeout,
+        grace_period: command.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD_MS),
+        env: command.env.clone(),
+        clear_env: command.clear_env,
+        cwd: command.cwd.clone(),
+        stdin: command.stdin.as_ref().map(stdin),
+        expect: describe_expectations(&command.expect),
+        capture: command.capture.as_ref().map(capture),
+        retry: command.retry.as_ref().map(|retry| RetryPlan {
+            retries: retry.retries,
+            delay: retry.delay,
+            backoff: retry.backoff,
+            until: retry.until,
+        }),
+        continue_on_error: command.continue_on_error,
+        allow_failure: command.allow_failure,
+        max_duration: command.max_duration,
+        warn_duration: command.warn_duration,
+    }
+}
+
+fn shell_args(shell: &Shell) -> Vec<String> {
+    let mut args = vec![shell.0.clone()];
+    args.extend(shell.1.iter().cloned());
+    args
+}
+
+fn teardown_policy(policy: &TeardownPolicy) -> &'static str {
+    match policy {
+        TeardownPolicy::Always => "always",
+        TeardownPolicy::OnSuccess => "on_success",
+        TeardownPolicy::Never => "never",
+    }
+}
+
+fn readiness(probe: &ReadinessProbe) -> String {
+    match probe {
+        ReadinessProbe::Tcp(address) => format!("tcp {}", address),
+        ReadinessProbe::Http(url) => format!("http {}", url),
+        ReadinessProbe::Log(pattern) => format!("log matches {:?}", pattern),
+        ReadinessProbe::Command(command) => format!("command {:?}", command.cmd),
+    }
+}
+
+fn stdin(stdin: &Stdin) -> String {
+    match stdin {
+        Stdin::Text(text) => format!("text {:?}", text),
+        Stdin::File(path) => format!("file {}", path),
+    }
+}
+
+fn capture(capture: &Capture) -> String {
+    let from = match &capture.extract {
+        CaptureExtract::Stdout => "stdout".to_string(),
+        CaptureExtract::Regex(regex) => format!("stdout matching {:?}", regex),
+        CaptureExtract::Json { path, .. } => format!("json {}", path),
+    };
+    let trim = if capture.trim { ", trimmed" } else { "" };
+    format!("{} from {}{}", capture.name, from, trim)
+}
+
+fn retry_text(retry: &Retry) -> String {
+    let mut text = match retry.retries {
+        Some(retries) => format!("up to {} retries", retries),
+        None => "retries".to_string(),
+    };
+    text.push_str(&format!(", {}ms apart", retry.delay));
+    if retry.backoff != 1.0 {
+        text.push_str(&format!(", backoff {}", retry.backoff));
+    }
+    if let Some(until) = retry.until {
+        text.push_str(&format!(", until {}ms", until));
+    }
+    text
+}
+
+fn module_text(module: &ProcessingModule) -> String {
+    let mut text = format!(
+        "Shell: {}\nTeardown: {}\n",
+        shell_args(&module.shell).join(" "),
+        teardown_policy(&module.teardown_policy)
+    );
+
+    text.push_str("\nSetup\n");
+    text.push_str(&commands_text(module, &module.setup.commands, "  "));
+
+    if !module.services.is_empty() {
+        text.push_str("\nServices\n");
+        for (index, service) in module.services.iter().enumerate() {
+            let ready = match &service.ready {
+                Some(probe) => format!(
+                    "ready when {}, within {}ms",
+                    readiness(probe),
+                    service.startup_timeout
+                ),
+                None => "ready once started".to_string(),
+            };
+            text.push_str(&format!("  {}. {} ({})\n", index + 1, service.name, ready));
+            text.push_str(&command_text(module, &service.command, "     "));
+        }
+    }
+
+    let processing = match module.tests.processing_kind {
+        ProcessingKind::Serial => "one after the other".to_string(),
+        ProcessingKind::Parallel(max) => format!("in parallel, up to {} at a time", max),
+    };
+    let fail_fast = if module.tests.fail_fast {
+        ", stopping at the first failure"
+    } else {
+        ""
+    };
+    text.push_str(&format!("\nTests ({}{})\n", processing, fail_fast));
+    for (index, set) in module.tests.sets.iter().enumerate() {
+        let name = set
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("Test #{}", index + 1));
+        let tags = if set.tags.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", set.tags.join(", "))
+        };
+        let skipped = match &set.skip {
+            Some(reason) => format!(" (skipped: {})", reason),
+            None => String::new(),
+        };
+        text.push_str(&format!("  {}. {}{}{}\n", index + 1, name, tags, skipped));
+        if let Some(budget) = set.budget {
+            text.push_str(&format!("     budget: {}ms\n", budget));
+        }
+        if set.allow_failure {
+            text.push_str("     on failure: warn\n");
+        }
+        if let Some(duration) = duration_text(set.max_duration, set.warn_duration) {
+            text.push_str(&format!("     duration: {}\n", duration));
+        }
+        // Only tests with a setup or teardown list them, before_each and
+        // after_each hooks included
+        if !set.setup.is_empty() {
+            text.push_str("     setup:\n");
+            text.push_str(&commands_text(module, &set.setup, "        "));
+        }
+        text.push_str(&commands_text(module, &set.commands, "     "));
+        if !set.teardown.is_empty() {
+            text.push_str("     teardown:\n");
+            text.push_str(&commands_text(module, &set.teardown, "        "));
+        }
+    }
+
+    text.push_str("\nTeardown\n");
+    text.push_str(&commands_text(module, &module.teardown.commands, "  "));
+    text
+}
+
+fn commands_text(
+    module: &ProcessingModule,
+    commands: &[ExecutableCommand],
+    indent: &str,
+) -> String {
+    if commands.is_empty() {
+        return format!("{}(none)\n", indent);
+    }
+    commands
+        .iter()
+        .enumerate()
+        .map(|(index, command)| {
+            let label = match &command.name {
+                Some(name) => format!("{}{}. {}\n", indent, index + 1, name),
+                None => format!("{}{}.\n", indent, index + 1),
+            };
+            label + &command_text(module, command, &format!("{}   ", indent))
+        })
+        .collect()
+}
+
+// Like "at most 500ms, warning after 200ms"
+fn duration_text(max: Option<u64>, warn: Option<u64>) -> Option<String> {
+    let limits: Vec<String> = max
+        .map(|max| format!("at most {}ms", max))
+        .into_iter()
+        .chain(warn.map(|warn| format!("warning after {}ms", warn)))
+        .collect();
+    if limits.is_empty() {
+        None
+    } else {
+        Some(limits.join(", "))
+    }
+}
+
+// The command line, then its settings. The shell is only shown when it isn't
+// the one of the module.
+fn command_text(module: &ProcessingModule, command: &ExecutableCommand, indent: &str) -> String {
+    let mut lines: Vec<String> = command
+        .cmd
+        .trim()
+        .lines()
+        .map(|l| format!("$ {}", l))
+        .collect();
+    if command.shell != module.shell {
+        lines.push(format!("shell: {}", shell_args(&command.shell).join(" ")));
+    }
+    lines.push(format!(
+        "timeout: {}, grace period {}ms",
+        command
+            .timeout
+            .filter(|t| *t > 0)
+            .map(|t| format!("{}ms", t))
+            .unwrap_or_else(|| "none".to_string()),
+        command.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD_MS)
+    ));
+    if let Some(cwd) = &command.cwd {
+        lines.push(format!("cwd: {}", cwd));
+    }
+    if command.clear_env {
+        lines.push("env: cleared".to_string());
+    }
+    for (key, value) in command.env.iter() {
+        lines.push(format!("env: {}={}", key, value));
+    }
+    if let Some(input) = &command.stdin {
+        lines.push(format!("stdin: {}", stdin(input)));
+    }
+    lines.push(format!(
+        "expect: {}",
+        describe_expectations(&command.expect).join(", ")
+    ));
+    if let Some(c) = &command.capture {
+        lines.push(format!("capture: {}", capture(c)));
+    }
+    if let Some(retry) = &command.retry {
+        lines.push(format!("retry: {}", retry_text(retry)));
+    }
+    if let Some(duration) = duration_text(command.max_duration, command.warn_duration) {
+        lines.push(format!("duration: {}", duration));
+    }
+    if command.allow_failure {
+        lines.push("on failure: warn and continue".to_string());
+    } else if command.continue_on_error {
+        lines.push("on failure: continue".to_string());
+    }
+    lines
+        .iter()
+        .map(|line| format!("{}{}\n", indent, line))
+        .collect()
+}
+
+#[cfg(test)]
+use crate::parser::{prepare_file, FileType};
+
+#[cfg(test)]
+fn plan_module() -> ProcessingModule {
+    let config = r#"
+        version = "3"
+        shell = { path = "/bin/sh", args = ["-c"] }
+        teardown_policy = "on_success"
+
+        [[setup]]
+        cmd = "echo ${host}"
+        timeout = 1000
+        continue_on_error = true
+
+        [[before_each]]
+        name = "reset"
+        cmd = "curl -X DELETE ${host}/items"
+
+        [[test]]
+        name = "get"
+        tags = ["smoke"]
+        budget = 10000
+        max_duration = 5000
+        [[test.command]]
+        cmd = "curl ${host}/items"
+        expect = { exit_code = [0, 7], stdout_contains = "id" }
+        retries = 2
+        max_duration = 500
+        warn_duration = 200
+        [[test.teardown]]
+        cmd = "rm -f items"
+
+        [[test]]
+        name = "slow"
+        disabled = true
+        [[test.command]]
+        cmd = "sleep 10"
+
+        [vars]
+        host = "localhost"
+    "#;
+    let mut module = prepare_file(FileType::Toml, config.to_string(), &Variables::new()).unwrap();
+    module.tests.sets[1].commands[0].shell = Shell::default();
+    module
+}
+
+#[test]
+fn t_plan_text() {
+    let text = format_plan_text(&[("api.fcheck.toml".to_string(), plan_module())]);
+    assert_eq!(
+        text,
+        "==> api.fcheck.toml
+Shell: /bin/sh -c
+Teardown: on_success
+
+Setup
+  1.
+     $ echo localhost
+     timeout: 1000ms, grace period 2000ms
+     expect: exit code 0
+     on failure: continue
+
+Tests (one after the other)
+  1. get [smoke]
+     budget: 10000ms
+     duration: at most 5000ms
+     setup:
+        1. reset
+           $ curl -X DELETE localhost/items
+           timeout: none, grace period 2000ms
+           expect: exit code 0
+     1.
+        $ curl localhost/items
+        timeout: none, grace period 2000ms
+        expect: exit code 0 or 7, stdout contains \"id\"
+        retry: up to 2 retries, 500ms apart
+        duration: at most 500ms, warning after 200ms
+     teardown:
+        1.
+           $ rm -f items
+           timeout: none, grace period 2000ms
+           expect: exit code 0
+  2. slow (skipped: Disabled)
+     setup:
+        1. reset
+           $ curl -X DELETE localhost/items
+           timeout: none, grace period 2000ms
+           expect: exit code 0
+     1.
+        $ sleep 10
+        shell: /bin/bash -c
+        timeout: none, grace period 2000ms
+        expect: exit code 0
+
+Teardown
+  (none)
+"
+    );
+}
+
+#[test]
+fn t_plan_json() {
+    let json = format_plan_json(&[("api.fcheck.toml".to_string(), plan_module())]);
+    let plan: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(plan["shell"], serde_json::json!(["/bin/sh", "-c"]));
+    assert_eq!(plan["tests"]["processing"], "serial");
+    assert_eq!(
+        plan["tests"]["sets"][0]["commands"][0]["cmd"],
+        "curl localhost/items"
+    );
+    assert_eq!(
+        plan["tests"]["sets"][0]["commands"][0]["retry"]["retries"],
+        2
+    );
+    assert_eq!(plan["tests"]["sets"][1]["skipped"], "Disabled");
+    assert_eq!(plan["tests"]["sets"][0]["max_duration"], 5000);
+    assert_eq!(plan["tests"]["sets"][0]["budget"], 10000);
+    assert_eq!(
+        plan["tests"]["sets"][0]["commands"][0]["warn_duration"],
+        200
+    );
+    assert_eq!(plan["setup"]["commands"][0]["timeout"], 1000);
+    assert_eq!(plan["tests"]["sets"][1]["setup"][0]["name"], "reset");
+    assert_eq!(
+        plan["tests"]["sets"][0]["teardown"][0]["cmd"],
+        "rm -f items"
+    );
+
+    let mut parallel = plan_module();
+    parallel.tests.processing_kind = ProcessingKind::Parallel(4);
+    parallel.tests.fail_fast = true;
+    let json = format_plan_json(&[
+        ("a.fcheck.toml".to_string(), plan_module()),
+        ("b.fcheck.toml".to_string(), parallel),
+    ]);
+    let plan: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(plan["modules"][1]["file"], "b.fcheck.toml");
+    assert_eq!(plan["modules"][1]["tests"]["max_parallel"], 4);
+    assert_eq!(plan["modules"][1]["tests"]["fail_fast"], true);
+}
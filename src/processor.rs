@@ -1,117 +1,571 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
 use std::ffi::{OsStr, OsString};
-use std::fmt::Display;
 use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
 use std::io::Result as IoResult;
-use std::str::from_utf8;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(test)]
+use std::sync::Condvar;
+use std::sync::Mutex;
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use subprocess::{CaptureData, Exec, ExitStatus, Popen, PopenConfig, PopenError, Redirection};
+use subprocess::{ExitStatus, Popen, PopenConfig, Redirection};
 
+use crate::assertions::evaluate_assertions;
+#[cfg(test)]
+use crate::model::{Capture, CaptureExtract, CommandSetType, Retry, Shell};
 use crate::model::{
     CommandFamily, CommandFamilyResult, CommandResult, CommandSet, CommandSetResult,
-    CommandSetType, ExecutableCommand, ProcessingKind, ProcessingModule, ProcessingModuleResult,
-    Shell,
+    ExecutableCommand, ModuleRun, ProcessingKind, ProcessingModule, ProcessingModuleResult,
+    RunResult, Service, ServiceResult, Stdin, Stopwatch, TeardownPolicy, Timing, Variables,
 };
+#[cfg(test)]
+use crate::runner::DryRunRunner;
+use crate::runner::{BudgetRunner, CommandRunner, LocalRunner};
+use crate::services::{failed_service, start_service, RunningService, ServicesRunner};
+use crate::variables::{capture_output, with_variables};
 
-// To Do
-//     * If setup fails, don't run Tests
-//     * Support Dahl
+pub fn run(module: &ProcessingModule, on_event: &OnEvent) -> ProcessingModuleResult {
+    run_processingmodule(&LocalRunner, on_event, module)
+}
+
+// Emitted while the config files of a run are processed
+#[derive(Debug)]
+pub enum RunEvent<'a, 'b> {
+    ModuleStarted(&'a str),
+    Processing(&'a str, &'a ProcessingEvent<'b>),
+    ModuleFinished(&'a ModuleRun),
+}
+
+pub type OnRunEvent<'a> = dyn Fn(&RunEvent) + Sync + 'a;
+
+// Runs the module of every config file, up to `max_parallel` at the same time.
+// Results are in the order of the files.
+pub fn run_all(
+    modules: &[(String, ProcessingModule)],
+    max_parallel: usize,
+    runner: &dyn CommandRunner,
+    on_event: &OnRunEvent,
+) -> RunResult {
+    let stopwatch = Stopwatch::start();
+    let run_module = |(file, module): &(String, ProcessingModule)| {
+        on_event(&RunEvent::ModuleStarted(file));
+        let result = run_processingmodule(
+            runner,
+            &|event| on_event(&RunEvent::Processing(file, event)),
+            module,
+        );
+        let res = ModuleRun {
+            file: file.clone(),
+            result,
+        };
+        on_event(&RunEvent::ModuleFinished(&res));
+        res
+    };
+
+    if max_parallel <= 1 {
+        return RunResult {
+            modules: modules.iter().map(run_module).collect(),
+            timing: stopwatch.timing(),
+        };
+    }
 
-// New
-//     * Support running multiple config files
-//     * Run CommandSet in Parallel
-//         * Control the paralelism, default to n
-//     * (Optional) Write to Console in readable format (Not JSON)
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<ModuleRun>>> = Mutex::new(vec![None; modules.len()]);
+    thread::scope(|scope| {
+        for _ in 0..max_parallel.min(modules.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                match modules.get(index) {
+                    Some(module) => {
+                        let res = run_module(module);
+                        results.lock().expect("Result lock poisoned")[index] = Some(res);
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
 
-pub fn run(module: &ProcessingModule) -> ProcessingModuleResult {
-    run_processingmodule(&run_command, module)
+    RunResult {
+        modules: results
+            .into_inner()
+            .expect("Result lock poisoned")
+            .into_iter()
+            .map(|res| res.expect("Every module should have been run"))
+            .collect(),
+        timing: stopwatch.timing(),
+    }
+}
+
+// Emitted while a module runs, so progress can be reported before the end result.
+// Events from different sets can interleave when a CommandFamily is Parallel.
+#[derive(Debug)]
+pub enum ProcessingEvent<'a> {
+    SetStarted(&'a CommandSet),
+    // The set is disabled or filtered out, its commands are not run
+    SetSkipped(&'a CommandSet),
+    CommandStarted(&'a CommandSet, &'a ExecutableCommand),
+    CommandFinished(&'a CommandSet, &'a CommandResult, Duration),
+    // An attempt failed, the command is run again after the delay
+    CommandRetrying(&'a CommandSet, &'a CommandResult, usize, Duration),
+    SetFinished(&'a CommandSetResult, Duration),
+    ServiceStarting(&'a Service),
+    ServiceReady(&'a Service, Duration),
+    ServiceStopped(&'a ServiceResult),
 }
 
+pub type OnEvent<'a> = dyn Fn(&ProcessingEvent) + Sync + 'a;
+
 pub fn run_processingmodule(
-    run_cmd: &dyn Fn(&ExecutableCommand) -> CommandResult,
+    runner: &dyn CommandRunner,
+    on_event: &OnEvent,
     module: &ProcessingModule,
 ) -> ProcessingModuleResult {
-    let setup = run_commandset(true, &run_cmd, &module.setup);
-    //Need ability to exit if there was a failure
-    // StopOnSetupFailure = true && !setup.success()
-    if setup.success() {
-        let tests = run_commandfamily(&run_cmd, &module.tests);
-
-        let teardown = run_commandset(false, &run_cmd, &module.teardown);
-
-        ProcessingModuleResult {
-            module: module.clone(),
-            setup: setup,
-            tests: Some(tests),
-            teardown: Some(teardown),
+    let stopwatch = Stopwatch::start();
+    runner.before_module(module);
+    let setup = run_commandset(true, runner, on_event, &Variables::new(), &module.setup);
+
+    // Variables captured in setup can be used by every test and the teardown
+    let vars = &setup.captures;
+
+    let (running, failed) = if setup.success() && runner.starts_services() {
+        start_services(on_event, &module.services)
+    } else {
+        (Vec::new(), None)
+    };
+
+    // Tests are skipped if setup failed or a service didn't start
+    let tests = if setup.success() && failed.is_none() {
+        let runner = ServicesRunner::new(runner, &running);
+        Some(run_commandfamily(&runner, on_event, vars, &module.tests))
+    } else {
+        None
+    };
+
+    let run_teardown = match module.teardown_policy {
+        TeardownPolicy::Always => true,
+        TeardownPolicy::OnSuccess => {
+            setup.success() && tests.as_ref().map(|t| t.success()).unwrap_or(false)
         }
+        TeardownPolicy::Never => false,
+    };
+    let teardown = if run_teardown {
+        Some(run_commandset(
+            false,
+            runner.teardown_runner().unwrap_or(runner),
+            on_event,
+            vars,
+            &module.teardown,
+        ))
     } else {
-        ProcessingModuleResult {
-            module: module.clone(),
-            setup: setup,
-            tests: None,
-            teardown: None,
+        None
+    };
+
+    // Stopped after the teardown, which can still need them to clean up
+    let services = stop_services(on_event, running, failed);
+
+    let result = ProcessingModuleResult {
+        module: module.clone(),
+        setup,
+        services,
+        tests,
+        teardown,
+        timing: stopwatch.timing(),
+    };
+    runner.after_module(&result);
+    result
+}
+
+// Starts the services in order, until one of them fails to become ready.
+// Returns the running services and the result of the one that failed.
+fn start_services(
+    on_event: &OnEvent,
+    services: &[Service],
+) -> (Vec<RunningService>, Option<ServiceResult>) {
+    let mut running = Vec::new();
+    for service in services.iter() {
+        on_event(&ProcessingEvent::ServiceStarting(service));
+        let start = Instant::now();
+        match start_service(service) {
+            Ok(started) => match started.wait_until_ready() {
+                Ok(()) => {
+                    on_event(&ProcessingEvent::ServiceReady(service, start.elapsed()));
+                    running.push(started);
+                }
+                Err(error) => return (running, Some(started.stop(Some(error)))),
+            },
+            Err(error) => return (running, Some(failed_service(service, error))),
         }
     }
+    (running, None)
+}
+
+// Stops the services in reverse order, results are in the order they were declared
+fn stop_services(
+    on_event: &OnEvent,
+    running: Vec<RunningService>,
+    failed: Option<ServiceResult>,
+) -> Vec<ServiceResult> {
+    let mut results: Vec<ServiceResult> = running
+        .into_iter()
+        .rev()
+        .map(|service| {
+            let res = service.stop(None);
+            on_event(&ProcessingEvent::ServiceStopped(&res));
+            res
+        })
+        .collect();
+    results.reverse();
+    if let Some(failed) = failed {
+        on_event(&ProcessingEvent::ServiceStopped(&failed));
+        results.push(failed);
+    }
+    results
 }
 
 pub fn run_commandfamily(
-    run_cmd: &dyn Fn(&ExecutableCommand) -> CommandResult,
+    runner: &dyn CommandRunner,
+    on_event: &OnEvent,
+    vars: &Variables,
     family: &CommandFamily,
 ) -> CommandFamilyResult {
-    let mut results = Vec::new();
+    let stopwatch = Stopwatch::start();
+    // With fail_fast, no set is started after one failed. Sets running in
+    // parallel at that moment still finish.
+    let failed = AtomicBool::new(false);
+    let run_set = |set: &CommandSet| {
+        if family.fail_fast && failed.load(Ordering::SeqCst) && set.skip.is_none() {
+            return not_run_commandset(
+                on_event,
+                set,
+                "Not run, an earlier test failed (--fail-fast)",
+            );
+        }
+        let res = run_commandset(true, runner, on_event, vars, set);
+        if !res.success() && !res.allowed_failure() {
+            failed.store(true, Ordering::SeqCst);
+        }
+        res
+    };
+    let results = match family.processing_kind {
+        ProcessingKind::Serial => family.sets.iter().map(run_set).collect(),
+        ProcessingKind::Parallel(max) => run_commandsets_parallel(max, &run_set, &family.sets),
+    };
 
-    for set in family.sets.iter() {
-        let res = run_commandset(true, run_cmd, &set);
-        results.push(res);
-    }
     CommandFamilyResult {
         family: family.clone(),
         sets: results,
+        timing: stopwatch.timing(),
     }
 }
 
+// A set that is not started, its commands are reported as not run, which
+// fails it
+fn not_run_commandset(on_event: &OnEvent, set: &CommandSet, reason: &str) -> CommandSetResult {
+    let not_run = |command: &ExecutableCommand| CommandResult::NotRun {
+        command: command.clone(),
+        reason: reason.to_string(),
+        aborted: true,
+        timing: Stopwatch::start().timing(),
+    };
+    let result = CommandSetResult {
+        set: set.clone(),
+        setup: set.setup.iter().map(not_run).collect(),
+        results: set.commands.iter().map(not_run).collect(),
+        teardown: set.teardown.iter().map(not_run).collect(),
+        captures: Variables::new(),
+        timing: Stopwatch::start().timing(),
+    };
+    on_event(&ProcessingEvent::SetFinished(&result, Duration::ZERO));
+    result
+}
+
+// Runs the sets on a pool of `max` worker threads. Each worker takes the next
+// set that hasn't been started, so results are put back in declaration order.
+fn run_commandsets_parallel(
+    max: usize,
+    run_set: &(dyn Fn(&CommandSet) -> CommandSetResult + Sync),
+    sets: &[CommandSet],
+) -> Vec<CommandSetResult> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<CommandSetResult>>> = Mutex::new(vec![None; sets.len()]);
+    let workers = max.max(1).min(sets.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                match sets.get(index) {
+                    Some(set) => {
+                        let res = run_set(set);
+                        results.lock().expect("Result lock poisoned")[index] = Some(res);
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .expect("Result lock poisoned")
+        .into_iter()
+        .map(|res| res.expect("Every set should have been run"))
+        .collect()
+}
+
 pub fn run_commandset(
     stop_on_failure: bool,
-    run_cmd: &dyn Fn(&ExecutableCommand) -> CommandResult,
+    runner: &dyn CommandRunner,
+    on_event: &OnEvent,
+    vars: &Variables,
     set: &CommandSet,
 ) -> CommandSetResult {
-    let mut results = Vec::new();
-
-    for cmd in set.commands.iter() {
-        let res = run_cmd(&cmd);
-        results.push(res.clone());
-        if !res.success() && stop_on_failure {
-            break;
-        }
+    if set.skip.is_some() {
+        on_event(&ProcessingEvent::SetSkipped(set));
+        return CommandSetResult {
+            set: set.clone(),
+            setup: Vec::new(),
+            results: Vec::new(),
+            teardown: Vec::new(),
+            captures: Variables::new(),
+            timing: Stopwatch::start().timing(),
+        };
     }
 
-    CommandSetResult {
+    let stopwatch = Stopwatch::start();
+    let mut vars = vars.clone();
+    let mut captures = Variables::new();
+    on_event(&ProcessingEvent::SetStarted(set));
+
+    let budget = set.budget.map(|budget| {
+        BudgetRunner::new(
+            runner,
+            Duration::from_millis(budget),
+            &format!("Test budget of {}ms used up", budget),
+        )
+    });
+    let budget_runner: &dyn CommandRunner = match &budget {
+        Some(budget) => budget,
+        None => runner,
+    };
+
+    let mut run_commands =
+        |runner: &dyn CommandRunner, commands: &[ExecutableCommand], stop_on_failure: bool| {
+            let mut results = Vec::new();
+            for cmd in commands.iter() {
+                let cmd = with_variables(cmd, &vars);
+                on_event(&ProcessingEvent::CommandStarted(set, &cmd));
+                let (res, captured) = run_with_retries(runner, on_event, set, &cmd);
+                if let Some((name, value)) = captured {
+                    vars.insert(name.clone(), value.clone());
+                    captures.insert(name, value);
+                }
+                on_event(&ProcessingEvent::CommandFinished(
+                    set,
+                    &res,
+                    res.timing().duration,
+                ));
+                results.push(res.clone());
+                // The commands left after the budget ran out are reported as not run
+                let used_up = budget.as_ref().is_some_and(|budget| budget.used_up());
+                let keep_going = cmd.continue_on_error || cmd.allow_failure || used_up;
+                if !res.success() && stop_on_failure && !keep_going {
+                    break;
+                }
+            }
+            results
+        };
+
+    // The commands of a test only run after its whole setup passed, its
+    // teardown always runs, outside of the budget of the test and of the run
+    let setup = run_commands(budget_runner, &set.setup, true);
+    let results = if setup
+        .iter()
+        .all(|res| res.success() || res.allowed_failure())
+    {
+        run_commands(budget_runner, &set.commands, stop_on_failure)
+    } else {
+        Vec::new()
+    };
+    let teardown = run_commands(
+        runner.teardown_runner().unwrap_or(runner),
+        &set.teardown,
+        false,
+    );
+
+    let result = CommandSetResult {
         set: set.clone(),
-        results: results,
+        setup,
+        results,
+        teardown,
+        captures,
+        timing: stopwatch.timing(),
+    };
+    on_event(&ProcessingEvent::SetFinished(
+        &result,
+        result.timing.duration,
+    ));
+    result
+}
+
+// Runs the command again while it fails, as long as its retry settings allow.
+// Every attempt is kept when it was run more than once.
+fn run_with_retries(
+    runner: &dyn CommandRunner,
+    on_event: &OnEvent,
+    set: &CommandSet,
+    cmd: &ExecutableCommand,
+) -> (CommandResult, Option<(String, String)>) {
+    let stopwatch = Stopwatch::start();
+    let mut attempts = Vec::new();
+    let mut delay = cmd.retry.as_ref().map(|r| r.delay).unwrap_or(0) as f64;
+    loop {
+        let (res, captured) = capture_output(evaluate_assertions(runner.run(cmd)));
+        let retry = match &cmd.retry {
+            // A stopped service won't come back, nor a budget that ran out.
+            // A command that only took too long did what it does, running
+            // it again would repeat its side effects.
+            Some(retry)
+                if !res.success()
+                    && !res.slow()
+                    && !matches!(
+                        res,
+                        CommandResult::ServiceCrashed { .. } | CommandResult::NotRun { .. }
+                    ) =>
+            {
+                let retries_left = retry
+                    .retries
+                    .is_none_or(|retries| attempts.len() < retries as usize);
+                let wait = Duration::from_millis(delay as u64);
+                let before_deadline = retry.until.is_none_or(|until| {
+                    stopwatch.timing().duration + wait < Duration::from_millis(until)
+                });
+                delay *= retry.backoff;
+                if retries_left && before_deadline {
+                    Some(wait)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        match retry {
+            Some(wait) => {
+                on_event(&ProcessingEvent::CommandRetrying(
+                    set,
+                    &res,
+                    attempts.len() + 1,
+                    wait,
+                ));
+                attempts.push(res);
+                thread::sleep(wait);
+            }
+            None if attempts.is_empty() => return (res, captured),
+            None => {
+                attempts.push(res);
+                let timing = stopwatch.timing();
+                return (CommandResult::Retried { attempts, timing }, captured);
+            }
+        }
     }
 }
 
+// Time a command gets to stop after SIGTERM, when it timed out
+pub const DEFAULT_GRACE_PERIOD_MS: u64 = 2000;
+
 pub fn run_command(command: &ExecutableCommand) -> CommandResult {
-    let timeout = command.timeout.map(|t| Duration::from_millis(t));
+    let stopwatch = Stopwatch::start();
+    let timeout = command
+        .timeout
+        .filter(|timeout| *timeout > 0)
+        .map(Duration::from_millis);
+    let grace_period =
+        Duration::from_millis(command.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD_MS));
+    let full_command = shell_command(command);
+
+    let res_data = popen_config(command).and_then(|(config, input)| {
+        start_process(timeout, grace_period, &full_command, config, input)
+    });
+
+    translate_result(command, res_data, stopwatch.timing())
+}
+
+pub fn shell_command(command: &ExecutableCommand) -> Vec<String> {
     let mut full_command = Vec::new();
     full_command.push(command.shell.0.clone());
     full_command.extend(command.shell.1.clone());
     full_command.push(command.cmd.clone());
+    full_command
+}
 
-    let res_data = start_process(timeout, &full_command);
+// Returns the config of the process and the text to write to its stdin
+pub fn popen_config(
+    command: &ExecutableCommand,
+) -> Result<(PopenConfig, Option<Vec<u8>>), RunProcessError> {
+    let (stdin, input) = match &command.stdin {
+        None => (Redirection::None, None),
+        Some(Stdin::Text(text)) => (Redirection::Pipe, Some(text.clone().into_bytes())),
+        Some(Stdin::File(path)) => {
+            // Relative to the working directory of the command
+            let path = match &command.cwd {
+                Some(cwd) => Path::new(cwd).join(path),
+                None => Path::new(path).to_path_buf(),
+            };
+            let file = File::open(&path).map_err(|e| {
+                RunProcessError::ProcessCreateError(format!(
+                    "Failed to open stdin file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            (Redirection::File(file), None)
+        }
+    };
 
-    translate_result(&command, res_data)
+    // `None` inherits the environment of fcheck
+    let env = if command.clear_env || !command.env.is_empty() {
+        let mut env: BTreeMap<OsString, OsString> = if command.clear_env {
+            BTreeMap::new()
+        } else {
+            env::vars_os().collect()
+        };
+        env.extend(
+            command
+                .env
+                .iter()
+                .map(|(k, v)| (OsString::from(k), OsString::from(v))),
+        );
+        Some(env.into_iter().collect())
+    } else {
+        None
+    };
+
+    let config = PopenConfig {
+        stdin,
+        stdout: Redirection::Pipe,
+        stderr: Redirection::Pipe,
+        env,
+        cwd: command.cwd.as_ref().map(OsString::from),
+        // The command and everything it starts can be stopped together
+        #[cfg(unix)]
+        setpgid: true,
+        ..Default::default()
+    };
+    Ok((config, input))
 }
 
 struct CapturedData {
     stdout: Result<Vec<u8>, RunProcessError>,
     stderr: Result<Vec<u8>, RunProcessError>,
     exit_status: Result<Option<ExitStatus>, RunProcessError>,
+    // Signal that stopped the process after a timeout
+    signal: Option<String>,
 }
 
 #[derive(Debug)]
@@ -135,27 +589,33 @@ pub enum RunProcessError {
 
 fn start_process<S: AsRef<OsStr>>(
     timeout: Option<Duration>,
+    grace_period: Duration,
     args: &[S],
+    config: PopenConfig,
+    input: Option<Vec<u8>>,
 ) -> Result<CapturedData, RunProcessError> {
     // let args_str = args.iter().fold(String::new(), |agg, i| {
     //     format!("{:?}, {:?}", agg, i.as_ref().to_os_string())
     // });
     // println!("Start Process = agrs: {:?}", args_str);
 
-    let mut p = Popen::create(
-        args,
-        PopenConfig {
-            stdin: Redirection::None,
-            stdout: Redirection::Pipe,
-            stderr: Redirection::Pipe,
-            ..Default::default()
-        },
-    )
-    .map_err(|err| RunProcessError::ProcessCreateError(err.to_string()))?;
+    let mut p = Popen::create(args, config)
+        .map_err(|err| RunProcessError::ProcessCreateError(err.to_string()))?;
+    // The process leads its own group, see `popen_config`
+    let pgid = p.pid();
+    let _group = ProcessGroup::new(pgid);
 
     // This should only fail if `Redirection::Pipe` is not defined in `PopenConfig`....I think
     let (stdout, stderr) = (p.stdout.take().unwrap(), p.stderr.take().unwrap());
 
+    // Written from a thread so a process that doesn't read all its input can't block
+    // fcheck. The pipe is closed when the thread ends, the process then sees EOF.
+    if let (Some(input), Some(mut stdin)) = (input, p.stdin.take()) {
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
     fn spawn_thread(mut redirect: File) -> JoinHandle<IoResult<Vec<u8>>> {
         thread::spawn(move || {
             let mut buffer = Vec::new();
@@ -174,7 +634,7 @@ fn start_process<S: AsRef<OsStr>>(
             .map_err(|err| RunProcessError::ProcessRuntimeError(err.to_string())),
         None => p
             .wait()
-            .map(|e| Some(e))
+            .map(Some)
             .map_err(|err| RunProcessError::ProcessRuntimeError(err.to_string())),
     };
 
@@ -197,34 +657,161 @@ fn start_process<S: AsRef<OsStr>>(
         }
     }
 
+    // `status == Ok(None)` means a timeout occured. The process is stopped before
+    // reading its output, since processes it started keep the pipes open until they end.
+    let signal = match status {
+        Ok(None) => Some(stop_process(&mut p, pgid, grace_period, &|| {
+            out_handle.is_finished() && err_handle.is_finished()
+        })?),
+        _ => None,
+    };
+
     let out = collapse(out_handle.join());
     let err = collapse(err_handle.join());
 
-    // `status == Ok(None)` means a timeout occured
-    if let Ok(st) = status {
-        if st.is_none() {
-            //May not want to error out... As long as we can kill the process then were good.
-            //Maybe log if the process can't be killed??
-            p.kill()
-                .map_err(|e| RunProcessError::KillProcessError(e.to_string()))?;
-            p.wait()
-                .map_err(|e| RunProcessError::KillWaitError(e.to_string()))?;
-        }
-    }
-
     // println!("Command Result: {:?}, {:?}, {:?}", out, err, status);
 
     Ok(CapturedData {
         stdout: out,
         stderr: err,
         exit_status: status,
+        signal,
     })
 }
 
+// Sends SIGTERM to the process group of the command, and SIGKILL when the
+// command or a process it started still runs after the grace period. A process
+// counts as stopped once it closed stdout and stderr, processes that ended but
+// weren't reaped yet would otherwise still be in the group. Returns the signal
+// that stopped the command.
+#[cfg(unix)]
+pub fn stop_process(
+    p: &mut Popen,
+    pgid: Option<u32>,
+    grace_period: Duration,
+    output_closed: &dyn Fn() -> bool,
+) -> Result<String, RunProcessError> {
+    let signal_group = |signal| {
+        if let Some(pgid) = pgid {
+            // Fails with ESRCH when every process of the group is gone
+            unsafe { libc::killpg(pgid as libc::pid_t, signal) };
+        }
+    };
+
+    signal_group(libc::SIGTERM);
+    let deadline = Instant::now() + grace_period;
+    p.wait_timeout(grace_period)
+        .map_err(|e| RunProcessError::KillWaitError(e.to_string()))?;
+    while !output_closed() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let stopped = output_closed() && p.poll().is_some();
+
+    // Also stops processes that closed their output but ignored SIGTERM
+    signal_group(libc::SIGKILL);
+    if stopped {
+        Ok("SIGTERM".to_string())
+    } else {
+        p.kill()
+            .map_err(|e| RunProcessError::KillProcessError(e.to_string()))?;
+        p.wait()
+            .map_err(|e| RunProcessError::KillWaitError(e.to_string()))?;
+        Ok("SIGKILL".to_string())
+    }
+}
+
+// Process groups of the commands and services running. They don't get the
+// signals sent to fcheck, see `stop_process_groups`.
+static PROCESS_GROUPS: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+fn process_groups() -> std::sync::MutexGuard<'static, BTreeSet<u32>> {
+    PROCESS_GROUPS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Keeps a process group in `PROCESS_GROUPS` until it is dropped, once the
+// process was reaped
+pub struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    pub fn new(pgid: Option<u32>) -> ProcessGroup {
+        if let Some(pgid) = pgid {
+            let mut groups = process_groups();
+            // Started while fcheck stops, it would be left running
+            #[cfg(unix)]
+            if interrupted() {
+                unsafe { libc::killpg(pgid as libc::pid_t, libc::SIGKILL) };
+            }
+            groups.insert(pgid);
+        }
+        ProcessGroup(pgid)
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            process_groups().remove(&pgid);
+        }
+    }
+}
+
+// Stops the commands and services still running when fcheck is interrupted,
+// like `stop_process` does: SIGTERM, then SIGKILL to the groups left after
+// the grace period.
+#[cfg(unix)]
+pub fn stop_process_groups(grace_period: Duration) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    let signal_groups = |signal| {
+        for pgid in process_groups().iter() {
+            unsafe { libc::killpg(*pgid as libc::pid_t, signal) };
+        }
+    };
+
+    signal_groups(libc::SIGTERM);
+    let deadline = Instant::now() + grace_period;
+    while !process_groups().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    signal_groups(libc::SIGKILL);
+}
+
+#[cfg(not(unix))]
+pub fn stop_process_groups(_grace_period: Duration) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(not(unix))]
+pub fn stop_process(
+    p: &mut Popen,
+    _pgid: Option<u32>,
+    _grace_period: Duration,
+    _output_closed: &dyn Fn() -> bool,
+) -> Result<String, RunProcessError> {
+    p.kill()
+        .map_err(|e| RunProcessError::KillProcessError(e.to_string()))?;
+    p.wait()
+        .map_err(|e| RunProcessError::KillWaitError(e.to_string()))?;
+    Ok("SIGKILL".to_string())
+}
+
+#[test]
+fn t_process_groups() {
+    // Not a real group, it is only signaled when fcheck is interrupted
+    let group = ProcessGroup::new(Some(u32::MAX));
+    assert!(process_groups().contains(&u32::MAX));
+    drop(group);
+    assert!(!process_groups().contains(&u32::MAX));
+}
+
 #[test]
 fn test_sleep_with_timeout_fails() {
     let timeout = Duration::from_millis(100);
-    let args = &vec!["sh", "-c", "echo hello && sleep 1"];
+    let args = &["sh", "-c", "echo hello && sleep 1"];
 
     let mut p = Popen::create(
         args,
@@ -263,30 +850,31 @@ fn test_sleep_with_timeout_fails() {
     assert_eq!("", String::from_utf8(err.unwrap()).unwrap());
 }
 
+pub fn translate_error(err: RunProcessError) -> String {
+    match err {
+        RunProcessError::ProcessCreateError(err) => format!("ProcessCreateError: {}", err),
+        RunProcessError::ProcessRuntimeError(err) => format!("ProcessRuntimeError: {}", err),
+        RunProcessError::RedirectReadFailed(err) => format!("RedirectReadFailed: {}", err),
+        RunProcessError::ThreadJoinError(err) => format!("ThreadJoinError: {}", err),
+        RunProcessError::KillProcessError(err) => format!("KillProcessError: {}", err),
+        RunProcessError::KillWaitError(err) => format!("KillWaitError: {}", err),
+    }
+}
+
 fn translate_result(
     command: &ExecutableCommand,
     result: Result<CapturedData, RunProcessError>,
+    timing: Timing,
 ) -> CommandResult {
-    fn translate_error(err: RunProcessError) -> String {
-        match err {
-            RunProcessError::ProcessCreateError(err) => format!("ProcessCreateError: {}", err),
-            RunProcessError::ProcessRuntimeError(err) => format!("ProcessRuntimeError: {}", err),
-            RunProcessError::RedirectReadFailed(err) => format!("RedirectReadFailed: {}", err),
-            RunProcessError::ThreadJoinError(err) => format!("ThreadJoinError: {}", err),
-            RunProcessError::KillProcessError(err) => format!("KillProcessError: {}", err),
-            RunProcessError::KillWaitError(err) => format!("KillWaitError: {}", err),
-        }
-    }
-
     match result {
         Ok(res) => {
             let stdout = res.stdout.map_or_else(
                 |e| format!("Fcheck error on stdout. {}", translate_error(e)),
-                |v| from_utf8_lossy(v),
+                from_utf8_lossy,
             );
             let stderr = res.stderr.map_or_else(
                 |e| format!("Fcheck error on stderr. {}", translate_error(e)),
-                |v| from_utf8_lossy(v),
+                from_utf8_lossy,
             );
             match res.exit_status {
                 Ok(opt_exit_status) => match opt_exit_status {
@@ -294,49 +882,59 @@ fn translate_result(
                         // https://docs.rs/subprocess/0.1.18/subprocess/enum.ExitStatus.html
                         ExitStatus::Exited(s) => CommandResult::StandardResult {
                             command: command.clone(),
-                            stdout: stdout,
-                            stderr: stderr,
+                            stdout,
+                            stderr,
                             exit_code: s.to_owned(),
+                            assertions: Vec::new(),
+                            timing,
                         },
                         ExitStatus::Signaled(s) => CommandResult::IrregularExitCode {
                             command: command.clone(),
-                            stdout: stdout,
-                            stderr: stderr,
+                            stdout,
+                            stderr,
                             exit_code: format!("Signaled({})", s),
+                            timing,
                         },
                         ExitStatus::Other(s) => CommandResult::IrregularExitCode {
                             command: command.clone(),
-                            stdout: stdout,
-                            stderr: stderr,
+                            stdout,
+                            stderr,
                             exit_code: format!("Other({})", s),
+                            timing,
                         },
                         ExitStatus::Undetermined => CommandResult::IrregularExitCode {
                             command: command.clone(),
-                            stdout: stdout,
-                            stderr: stderr,
+                            stdout,
+                            stderr,
                             exit_code: "Undetermined".to_string(),
+                            timing,
                         },
                     },
                     None => {
                         //Timeout Occurred
                         CommandResult::Timeout {
                             command: command.clone(),
-                            stdout: stdout,
-                            stderr: stderr,
+                            stdout,
+                            stderr,
+                            signal: res.signal.unwrap_or_default(),
+                            reason: None,
+                            timing,
                         }
                     }
                 },
                 Err(err) => CommandResult::RuntimeError {
                     command: command.clone(),
-                    stdout: stdout,
-                    stderr: stderr,
+                    stdout,
+                    stderr,
                     error: format!("Runtime error occured: {}", translate_error(err)),
+                    timing,
                 },
             }
         }
         Err(e) => CommandResult::OsError {
             command: command.clone(),
             error: format!("OS error occured: {}", translate_error(e)),
+            timing,
         },
     }
 }
@@ -353,6 +951,7 @@ fn t_exec_simple() {
         timeout: None,
         shell: Shell::default(),
         cmd: "echo Hello".to_string(),
+        ..Default::default()
     };
 
     let res = run_command(&cmd);
@@ -363,6 +962,7 @@ fn t_exec_simple() {
             stdout,
             stderr,
             exit_code,
+            ..
         } => {
             assert_eq!(command, cmd);
             assert_eq!(stdout, "Hello\n");
@@ -385,6 +985,7 @@ fn t_exec_multiline() {
             echo hello;
         "#
         .to_string(),
+        ..Default::default()
     };
 
     let res = run_command(&cmd);
@@ -395,6 +996,7 @@ fn t_exec_multiline() {
             stdout,
             stderr,
             exit_code,
+            ..
         } => {
             assert_eq!(command, cmd);
             assert_eq!(stdout, "Hello\nhello\n");
@@ -419,6 +1021,7 @@ fn t_exec_runs_with_bash() {
             done;
         "#
         .to_string(),
+        ..Default::default()
     };
 
     let res = run_command(&cmd);
@@ -428,6 +1031,7 @@ fn t_exec_runs_with_bash() {
             stdout,
             stderr,
             exit_code,
+            ..
         } => {
             assert_eq!(command, cmd);
             assert_eq!(stdout, "0\n1\n2\n");
@@ -441,8 +1045,6 @@ fn t_exec_runs_with_bash() {
 #[test]
 fn t_execset_simple() {
     let cmds = CommandSet {
-        name: None,
-        set_type: CommandSetType::Test,
         commands: vec![
             ExecutableCommand {
                 name: Option::None,
@@ -450,6 +1052,7 @@ fn t_execset_simple() {
                 timeout: None,
                 shell: Shell::default(),
                 cmd: "echo Hello".to_string(),
+                ..Default::default()
             },
             ExecutableCommand {
                 name: Option::None,
@@ -457,12 +1060,13 @@ fn t_execset_simple() {
                 timeout: None,
                 shell: Shell::default(),
                 cmd: "echo Hello".to_string(),
+                ..Default::default()
             },
         ],
-        processing_kind: ProcessingKind::Serial,
+        ..Default::default()
     };
 
-    let res = run_commandset(true, &run_command, &cmds);
+    let res = run_commandset(true, &run_command, &|_| (), &Variables::new(), &cmds);
 
     assert_eq!(res.results.len(), 2);
 }
@@ -470,8 +1074,6 @@ fn t_execset_simple() {
 #[test]
 fn t_execset_stop_on_failure() {
     let cmds = CommandSet {
-        name: None,
-        set_type: CommandSetType::Test,
         commands: vec![
             ExecutableCommand {
                 name: Option::None,
@@ -479,6 +1081,7 @@ fn t_execset_stop_on_failure() {
                 timeout: None,
                 shell: Shell::default(),
                 cmd: "exit 1".to_string(),
+                ..Default::default()
             },
             ExecutableCommand {
                 name: Option::None,
@@ -486,12 +1089,881 @@ fn t_execset_stop_on_failure() {
                 timeout: None,
                 shell: Shell::default(),
                 cmd: "echo Hello".to_string(),
+                ..Default::default()
             },
         ],
-        processing_kind: ProcessingKind::Serial,
+        ..Default::default()
     };
 
-    let res = run_commandset(true, &run_command, &cmds);
+    let res = run_commandset(true, &run_command, &|_| (), &Variables::new(), &cmds);
 
     assert_eq!(res.results.len(), 1);
 }
+
+#[cfg(test)]
+fn sleep_set(name: &str, millis: u64) -> CommandSet {
+    CommandSet {
+        name: Some(name.to_string()),
+        commands: vec![ExecutableCommand {
+            name: Option::None,
+            description: Option::None,
+            timeout: None,
+            shell: Shell::default(),
+            cmd: format!("sleep {}", millis),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+fn fake_command(command: &ExecutableCommand) -> CommandResult {
+    CommandResult::StandardResult {
+        command: command.clone(),
+        stdout: String::new(),
+        stderr: String::new(),
+        exit_code: 0,
+        assertions: Vec::new(),
+        timing: Timing::default(),
+    }
+}
+
+#[test]
+fn t_execfamily_parallel_keeps_order() {
+    let family = CommandFamily {
+        sets: vec![
+            sleep_set("slow", 300),
+            sleep_set("medium", 150),
+            sleep_set("fast", 0),
+        ],
+        processing_kind: ProcessingKind::Parallel(3),
+        fail_fast: false,
+    };
+
+    let res = run_commandfamily(
+        &|cmd: &ExecutableCommand| {
+            let millis: u64 = cmd.cmd.trim_start_matches("sleep ").parse().unwrap();
+            thread::sleep(Duration::from_millis(millis));
+            fake_command(cmd)
+        },
+        &|_| (),
+        &Variables::new(),
+        &family,
+    );
+
+    let names: Vec<_> = res
+        .sets
+        .iter()
+        .map(|s| s.set.name.clone().unwrap())
+        .collect();
+    assert_eq!(names, vec!["slow", "medium", "fast"]);
+    assert!(res.success());
+}
+
+#[test]
+fn t_continue_and_allow_failure() {
+    let command = |cmd: &str| ExecutableCommand {
+        cmd: cmd.to_string(),
+        ..Default::default()
+    };
+    let set = |commands: Vec<ExecutableCommand>| {
+        let mut set = sleep_set("set", 0);
+        set.commands = commands;
+        set
+    };
+    let failing = |continue_on_error, allow_failure| ExecutableCommand {
+        continue_on_error,
+        allow_failure,
+        ..command("exit 1")
+    };
+    let run =
+        |set: &CommandSet| run_commandset(true, &run_command, &|_| (), &Variables::new(), set);
+
+    let res = run(&set(vec![failing(true, false), command("echo after")]));
+    assert!(!res.success());
+    assert_eq!(res.results.len(), 2);
+    assert!(res.results[1].success());
+
+    let res = run(&set(vec![failing(false, true), command("echo after")]));
+    assert!(res.success());
+    assert!(res.results[0].allowed_failure());
+    assert_eq!(res.results.len(), 2);
+
+    let mut allowed = set(vec![command("exit 1"), command("echo after")]);
+    allowed.allow_failure = true;
+    let res = run(&allowed);
+    assert!(!res.success());
+    assert!(res.allowed_failure());
+    assert_eq!(res.results.len(), 1);
+}
+
+#[test]
+fn t_set_setup_and_teardown() {
+    let command = |cmd: &str| ExecutableCommand {
+        cmd: cmd.to_string(),
+        ..Default::default()
+    };
+    let mut set = sleep_set("set", 0);
+    set.setup = vec![command("echo setup")];
+    set.commands = vec![command("echo test"), command("exit 1")];
+    set.teardown = vec![command("exit 2"), command("echo teardown")];
+
+    let res = run_commandset(true, &run_command, &|_| (), &Variables::new(), &set);
+    assert!(!res.success());
+    assert!(res.setup[0].success());
+    assert_eq!(res.results.len(), 2);
+    // The teardown runs after a failure, and past its own failures
+    assert_eq!(res.teardown.len(), 2);
+    assert_eq!(res.teardown[1].stdout(), "teardown\n");
+    assert_eq!(res.all_results().count(), 5);
+
+    set.setup = vec![command("exit 1"), command("echo setup")];
+    let res = run_commandset(true, &run_command, &|_| (), &Variables::new(), &set);
+    assert!(!res.success());
+    assert_eq!(res.setup.len(), 1);
+    assert!(res.results.is_empty());
+    assert_eq!(res.teardown.len(), 2);
+}
+
+#[test]
+fn t_execfamily_fail_fast() {
+    let set = |name: &str, cmd: &str| {
+        let mut set = sleep_set(name, 0);
+        set.commands[0].cmd = cmd.to_string();
+        set
+    };
+    let mut allowed = set("allowed", "exit 1");
+    allowed.allow_failure = true;
+    let mut family = CommandFamily {
+        sets: vec![
+            set("first", "exit 0"),
+            allowed,
+            set("failing", "exit 1"),
+            set("after", "echo after"),
+        ],
+        processing_kind: ProcessingKind::Serial,
+        fail_fast: true,
+    };
+
+    let res = run_commandfamily(&run_command, &|_| (), &Variables::new(), &family);
+    let not_run: Vec<_> = res.sets.iter().map(|s| s.not_run()).collect();
+    assert_eq!(
+        not_run,
+        vec![
+            None,
+            None,
+            None,
+            Some("Not run, an earlier test failed (--fail-fast)".to_string())
+        ]
+    );
+    assert!(!res.sets[3].success() && !res.sets[3].skipped());
+    assert!(matches!(
+        res.sets[3].results[..],
+        [CommandResult::NotRun { aborted: true, .. }]
+    ));
+
+    family.fail_fast = false;
+    let res = run_commandfamily(&run_command, &|_| (), &Variables::new(), &family);
+    assert!(res.sets[3].success() && !res.sets[3].skipped());
+}
+
+#[test]
+fn t_execfamily_parallel_bounded() {
+    let running = AtomicUsize::new(0);
+    let max_running = AtomicUsize::new(0);
+    // The first 3 commands wait for each other, which only ends in time if
+    // they run at the same time
+    let started = Mutex::new(0);
+    let all_started = Condvar::new();
+    let timed_out = AtomicBool::new(false);
+    let family = CommandFamily {
+        sets: (0..8).map(|i| sleep_set(&i.to_string(), 0)).collect(),
+        processing_kind: ProcessingKind::Parallel(3),
+        fail_fast: false,
+    };
+
+    let res = run_commandfamily(
+        &|cmd: &ExecutableCommand| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            let mut count = started.lock().unwrap();
+            *count += 1;
+            all_started.notify_all();
+            let (count, wait) = all_started
+                .wait_timeout_while(count, Duration::from_secs(10), |count| *count < 3)
+                .unwrap();
+            drop(count);
+            if wait.timed_out() {
+                timed_out.store(true, Ordering::SeqCst);
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+            fake_command(cmd)
+        },
+        &|_| (),
+        &Variables::new(),
+        &family,
+    );
+
+    assert_eq!(res.sets.len(), 8);
+    assert!(!timed_out.load(Ordering::SeqCst));
+    assert!(max_running.load(Ordering::SeqCst) <= 3);
+}
+
+#[test]
+fn t_execset_emits_events() {
+    let events = Mutex::new(Vec::new());
+    let set = CommandSet {
+        name: Some("events".to_string()),
+        commands: vec![
+            ExecutableCommand {
+                name: Some("first".to_string()),
+                description: Option::None,
+                timeout: None,
+                shell: Shell::default(),
+                cmd: "exit 1".to_string(),
+                ..Default::default()
+            },
+            ExecutableCommand {
+                name: Some("second".to_string()),
+                description: Option::None,
+                timeout: None,
+                shell: Shell::default(),
+                cmd: "echo Hello".to_string(),
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    };
+
+    run_commandset(
+        true,
+        &|cmd: &ExecutableCommand| CommandResult::StandardResult {
+            command: cmd.clone(),
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: 1,
+            assertions: Vec::new(),
+            timing: Timing::default(),
+        },
+        &|event: &ProcessingEvent| {
+            let name = match event {
+                ProcessingEvent::SetStarted(set) => format!("set started {:?}", set.name),
+                ProcessingEvent::CommandStarted(_, cmd) => format!("cmd started {:?}", cmd.name),
+                ProcessingEvent::CommandFinished(_, res, _) => {
+                    format!("cmd finished {}", res.success())
+                }
+                ProcessingEvent::SetFinished(res, _) => format!("set finished {}", res.success()),
+                _ => "service".to_string(),
+            };
+            events.lock().unwrap().push(name);
+        },
+        &Variables::new(),
+        &set,
+    );
+
+    assert_eq!(
+        events.into_inner().unwrap(),
+        vec![
+            "set started Some(\"events\")",
+            "cmd started Some(\"first\")",
+            "cmd finished false",
+            "set finished false",
+        ]
+    );
+}
+
+#[cfg(test)]
+fn policy_module(setup_cmd: &str, policy: TeardownPolicy) -> ProcessingModule {
+    let set = |set_type: CommandSetType, cmd: &str| CommandSet {
+        set_type,
+        commands: vec![ExecutableCommand {
+            name: Option::None,
+            description: Option::None,
+            timeout: None,
+            shell: Shell::default(),
+            cmd: cmd.to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    ProcessingModule {
+        shell: Shell::default(),
+        setup: set(CommandSetType::Setup, setup_cmd),
+        services: Vec::new(),
+        tests: CommandFamily {
+            sets: vec![set(CommandSetType::Test, "echo test")],
+            processing_kind: ProcessingKind::Serial,
+            fail_fast: false,
+        },
+        teardown: set(CommandSetType::Teardown, "echo teardown"),
+        teardown_policy: policy,
+    }
+}
+
+#[test]
+fn t_teardown_policy() {
+    let run = |module: &ProcessingModule| run_processingmodule(&run_command, &|_| (), module);
+
+    // Teardown runs after a failed setup, tests don't
+    let res = run(&policy_module("exit 1", TeardownPolicy::Always));
+    assert!(res.tests.is_none());
+    assert_eq!(res.tests_skipped_reason(), Some("Setup failed".to_string()));
+    assert!(res.teardown.map(|t| t.success()).unwrap_or(false));
+
+    let res = run(&policy_module("exit 1", TeardownPolicy::OnSuccess));
+    assert!(res.teardown.is_none());
+
+    let res = run(&policy_module("echo setup", TeardownPolicy::OnSuccess));
+    assert!(res.teardown.is_some());
+    assert!(res.success());
+
+    let res = run(&policy_module("echo setup", TeardownPolicy::Never));
+    assert!(res.teardown.is_none());
+    assert_eq!(
+        res.teardown_skipped_reason(),
+        Some("Teardown policy is 'never'".to_string())
+    );
+    assert!(res.success());
+}
+
+#[cfg(test)]
+struct HookRunner {
+    calls: Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl CommandRunner for HookRunner {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        self.calls.lock().unwrap().push(command.cmd.clone());
+        fake_command(command)
+    }
+
+    fn before_module(&self, _module: &ProcessingModule) {
+        self.calls.lock().unwrap().push("before".to_string());
+    }
+
+    fn after_module(&self, result: &ProcessingModuleResult) {
+        let call = format!("after, success {}", result.success());
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+#[test]
+fn t_runners() {
+    let module = policy_module("echo setup", TeardownPolicy::Always);
+    let runner = HookRunner {
+        calls: Mutex::new(Vec::new()),
+    };
+    run_processingmodule(&runner, &|_| (), &module);
+    assert_eq!(
+        runner.calls.into_inner().unwrap(),
+        vec![
+            "before",
+            "echo setup",
+            "echo test",
+            "echo teardown",
+            "after, success true"
+        ]
+    );
+
+    // A dry run doesn't start the services either
+    let mut module = policy_module("exit 1", TeardownPolicy::Always);
+    module.services = vec![Service {
+        name: "api".to_string(),
+        command: ExecutableCommand {
+            cmd: "exit 1".to_string(),
+            ..Default::default()
+        },
+        ready: None,
+        startup_timeout: 1000,
+        probe_interval: 20,
+    }];
+    let res = run_processingmodule(&DryRunRunner, &|_| (), &module);
+    assert!(res.success());
+    assert!(res.services.is_empty());
+    match &res.tests.unwrap().sets[0].results[0] {
+        CommandResult::NotRun {
+            command, reason, ..
+        } => {
+            assert_eq!(
+                (command.cmd.as_str(), reason.as_str()),
+                ("echo test", "Dry run")
+            )
+        }
+        other => panic!("Expected a command that didn't run, got {:?}", other),
+    }
+}
+
+#[test]
+fn t_timings() {
+    let module = policy_module("sleep 0.1", TeardownPolicy::Always);
+    let res = run_processingmodule(&run_command, &|_| (), &module);
+
+    // Every level takes at least as long as what ran in it
+    let command = res.setup.results[0].timing();
+    assert!(command.duration >= Duration::from_millis(100));
+    assert!(res.setup.timing.started <= command.started);
+    assert!(res.setup.timing.duration >= command.duration);
+    let tests = res.tests.as_ref().unwrap();
+    assert!(tests.timing.duration >= tests.sets[0].timing.duration);
+    assert!(
+        res.timing.duration
+            >= res.setup.timing.duration
+                + tests.timing.duration
+                + res.teardown.as_ref().unwrap().timing.duration
+    );
+}
+
+#[test]
+fn t_max_duration() {
+    // Each command takes as many milliseconds as its cmd says
+    let runner = |command: &ExecutableCommand| CommandResult::StandardResult {
+        command: command.clone(),
+        stdout: String::new(),
+        stderr: String::new(),
+        exit_code: 0,
+        assertions: Vec::new(),
+        timing: Timing {
+            started: std::time::SystemTime::now(),
+            duration: Duration::from_millis(command.cmd.parse().unwrap()),
+        },
+    };
+    let mut module = policy_module("0", TeardownPolicy::Always);
+    module.teardown.commands[0].cmd = "0".to_string();
+    let limited = |cmd: &str, max, warn, exit_code| {
+        let mut set = module.setup.clone();
+        set.set_type = CommandSetType::Test;
+        set.commands[0].cmd = cmd.to_string();
+        set.commands[0].max_duration = max;
+        set.commands[0].warn_duration = warn;
+        set.commands[0].expect.exit_codes = vec![exit_code];
+        set
+    };
+    module.tests.sets = vec![
+        limited("300", Some(200), None, 0),
+        limited("150", Some(200), Some(100), 0),
+        limited("300", Some(200), None, 1),
+    ];
+    let res = run_processingmodule(&runner, &|_| (), &module);
+    let tests = res.tests.unwrap();
+
+    let slow = &tests.sets[0];
+    assert!(!slow.success());
+    assert!(slow.slow());
+    assert!(slow.results[0].slow());
+    assert_eq!(
+        slow.slow_reason(),
+        Some("took 300ms, more than the max_duration of 200ms".to_string())
+    );
+
+    let warned = &tests.sets[1];
+    assert!(warned.success());
+    assert_eq!(
+        warned.results[0].duration_warning(),
+        Some("took 150ms, more than the warn_duration of 100ms".to_string())
+    );
+    assert_eq!(warned.duration_warning(), None);
+
+    // Failing for another reason is not slow
+    assert!(!tests.sets[2].slow());
+    assert_eq!(tests.sets[2].results[0].slow_reason(), None);
+
+    // The limits of a test apply to all of its commands together
+    let mut module = policy_module("sleep 0.1", TeardownPolicy::Always);
+    module.setup.max_duration = Some(50);
+    module.teardown.commands[0].cmd = "sleep 0.1".to_string();
+    module.teardown.warn_duration = Some(50);
+    let res = run_processingmodule(&run_command, &|_| (), &module);
+    assert!(res.setup.results[0].success());
+    assert!(res.setup.slow());
+    assert!(res.setup.over_max_duration().is_some());
+    assert!(!res.success());
+    let teardown = res.teardown.unwrap();
+    assert!(teardown.success());
+    assert!(teardown.duration_warning().is_some());
+}
+
+#[test]
+fn t_budget() {
+    let command = |cmd: &str, timeout| ExecutableCommand {
+        cmd: cmd.to_string(),
+        timeout,
+        ..Default::default()
+    };
+    let mut module = policy_module("sleep 0.1", TeardownPolicy::Always);
+    // A timeout of 0 is no timeout
+    module.setup.commands[0].timeout = Some(0);
+    module.tests.sets[0].budget = Some(200);
+    module.tests.sets[0].commands = vec![
+        command("sleep 0.1", Some(5000)),
+        command("sleep 5", None),
+        command("echo after", None),
+    ];
+
+    let res = run_processingmodule(&run_command, &|_| (), &module);
+    assert!(res.setup.success());
+    let set = &res.tests.as_ref().unwrap().sets[0];
+    assert!(!set.success());
+    assert!(set.results[0].success());
+    // Stopped when the budget ran out, the command after it is not run
+    match &set.results[1] {
+        CommandResult::Timeout {
+            command, reason, ..
+        } => {
+            assert!(command.timeout.unwrap() <= 100);
+            assert_eq!(reason.as_deref(), Some("Test budget of 200ms used up"));
+        }
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+    match &set.results[2] {
+        CommandResult::NotRun {
+            reason, aborted, ..
+        } => {
+            assert_eq!(reason, "Test budget of 200ms used up");
+            assert!(aborted)
+        }
+        other => panic!("Expected the command not to run, got {:?}", other),
+    }
+    assert!(set.timing.duration < Duration::from_millis(1000));
+
+    // A budget for a whole run
+    let runner = BudgetRunner::new(&LocalRunner, Duration::ZERO, "Run budget used up");
+    let res = run_processingmodule(&runner, &|_| (), &module);
+    assert!(!res.setup.success());
+    assert!(!res.setup.results[0].success());
+    // The teardown cleans up, even with the budget used up
+    let teardown = res.teardown.unwrap();
+    assert!(teardown.success());
+    assert_eq!(teardown.results[0].stdout(), "teardown\n");
+
+    // So does the teardown of a test that used up the budget of the run
+    let mut set = sleep_set("test", 5000);
+    set.teardown = vec![command("echo cleanup", None)];
+    let runner = BudgetRunner::new(
+        &LocalRunner,
+        Duration::from_millis(200),
+        "Run budget used up",
+    );
+    let res = run_commandset(true, &runner, &|_| (), &Variables::new(), &set);
+    assert!(matches!(
+        &res.results[0],
+        CommandResult::Timeout { reason: Some(reason), .. } if reason == "Run budget used up"
+    ));
+    assert!(res.teardown[0].success());
+    assert_eq!(res.teardown[0].stdout(), "cleanup\n");
+}
+
+#[test]
+fn t_captures() {
+    let capture = |name: &str, extract| {
+        Some(Capture {
+            name: name.to_string(),
+            trim: true,
+            extract,
+        })
+    };
+    let mut module = policy_module("echo abc", TeardownPolicy::Always);
+    module.setup.commands[0].capture = capture("token", CaptureExtract::Stdout);
+    module.tests.sets[0].commands = vec![
+        ExecutableCommand {
+            cmd: "echo id=${token}-1".to_string(),
+            capture: capture("id", CaptureExtract::Regex("id=(.*)".to_string())),
+            ..Default::default()
+        },
+        ExecutableCommand {
+            cmd: "echo ${id} ${UNKNOWN}".to_string(),
+            ..Default::default()
+        },
+    ];
+    module.teardown.commands[0].cmd = "echo ${token} ${id}".to_string();
+
+    let res = run_processingmodule(&run_command, &|_| (), &module);
+
+    assert!(res.success());
+    assert_eq!(res.setup.captures.get("token"), Some(&"abc".to_string()));
+    let test = &res.tests.unwrap().sets[0];
+    assert_eq!(test.captures.get("id"), Some(&"abc-1".to_string()));
+    assert_eq!(test.results[1].command().cmd, "echo abc-1 ${UNKNOWN}");
+    assert_eq!(test.results[1].stdout(), "abc-1\n");
+    // Captures of a test are not shared with the rest of the module
+    assert_eq!(
+        res.teardown.unwrap().results[0].command().cmd,
+        "echo abc ${id}"
+    );
+}
+
+#[test]
+fn t_exec_env_cwd_stdin() {
+    let dir = env::temp_dir();
+    let stdin_name = format!("fcheck-t-exec-stdin-{}.txt", std::process::id());
+    let stdin_file = dir.join(&stdin_name);
+    std::fs::write(&stdin_file, "from file\n").unwrap();
+
+    let mut env = BTreeMap::new();
+    env.insert("GREETING".to_string(), "hello".to_string());
+    let command = |cmd: &str| ExecutableCommand {
+        shell: Shell::default(),
+        cmd: cmd.to_string(),
+        env: env.clone(),
+        cwd: Some(dir.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+
+    let res = run_command(&command("echo $GREETING; pwd"));
+    assert_eq!(
+        res.stdout(),
+        format!("hello\n{}\n", dir.canonicalize().unwrap().to_string_lossy())
+    );
+
+    let res = run_command(&ExecutableCommand {
+        stdin: Some(Stdin::Text("some input".to_string())),
+        ..command("cat")
+    });
+    assert_eq!(res.stdout(), "some input");
+
+    let res = run_command(&ExecutableCommand {
+        stdin: Some(Stdin::File(stdin_name.clone())),
+        ..command("cat")
+    });
+    assert_eq!(res.stdout(), "from file\n");
+
+    let res = run_command(&ExecutableCommand {
+        clear_env: true,
+        ..command("echo \"$GREETING $HOME\"")
+    });
+    assert_eq!(res.stdout(), "hello \n");
+
+    let res = run_command(&ExecutableCommand {
+        stdin: Some(Stdin::File("missing.txt".to_string())),
+        ..command("cat")
+    });
+    assert!(!res.success());
+
+    let _ = std::fs::remove_file(&stdin_file);
+}
+
+#[test]
+fn t_exec_timeout_stops_process_group() {
+    let command = |cmd: &str| ExecutableCommand {
+        timeout: Some(100),
+        grace_period: Some(300),
+        shell: Shell::default(),
+        cmd: cmd.to_string(),
+        ..Default::default()
+    };
+
+    // The background sleep keeps stdout open, it has to be stopped too
+    let start = Instant::now();
+    let res = run_command(&command("echo started; sleep 5 & sleep 5"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    match res {
+        CommandResult::Timeout { stdout, signal, .. } => {
+            assert_eq!(stdout, "started\n");
+            assert_eq!(signal, "SIGTERM");
+        }
+        _ => panic!("Expected a timeout, got {:?}", res),
+    }
+
+    // SIGTERM is ignored, so SIGKILL is sent after the grace period
+    let start = Instant::now();
+    let res = run_command(&command("trap '' TERM; sleep 5 & sleep 5"));
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    match res {
+        CommandResult::Timeout { signal, .. } => assert_eq!(signal, "SIGKILL"),
+        _ => panic!("Expected a timeout, got {:?}", res),
+    }
+}
+
+#[test]
+fn t_services() {
+    let service = |name: &str, cmd: &str| Service {
+        name: name.to_string(),
+        command: ExecutableCommand {
+            shell: Shell::default(),
+            cmd: cmd.to_string(),
+            grace_period: Some(300),
+            ..Default::default()
+        },
+        ready: Some(crate::model::ReadinessProbe::Log("up".to_string())),
+        startup_timeout: 2000,
+        probe_interval: 20,
+    };
+
+    // The service exits while the tests run
+    let mut module = policy_module("echo setup", TeardownPolicy::Always);
+    module.services = vec![
+        service("db", "echo up; sleep 30"),
+        service("api", "echo up; sleep 0.3; exit 4"),
+    ];
+    module.tests.sets[0].commands[0].cmd = "sleep 0.6".to_string();
+    let res = run_processingmodule(&run_command, &|_| (), &module);
+    match &res.tests.as_ref().unwrap().sets[0].results[0] {
+        CommandResult::ServiceCrashed {
+            service,
+            exit_status,
+            ..
+        } => {
+            assert_eq!(service, "api");
+            assert_eq!(exit_status, "exited with code 4");
+        }
+        other => panic!("Expected a crashed service, got {:?}", other),
+    }
+    assert_eq!(res.services[0].exit_status, "stopped with SIGTERM");
+    assert!(res.services[0].success());
+    assert!(!res.services[1].success());
+    assert!(res.teardown.map(|t| t.success()).unwrap_or(false));
+
+    // The teardown runs while the services are still running
+    let pid = env::temp_dir().join(format!("fcheck-service-pid-{}", std::process::id()));
+    module.services = vec![service(
+        "db",
+        &format!("echo $$ > {}; echo up; sleep 30", pid.display()),
+    )];
+    module.tests.sets[0].commands[0].cmd = "true".to_string();
+    module.teardown.commands = vec![ExecutableCommand {
+        shell: Shell::default(),
+        cmd: format!("kill -0 $(cat {})", pid.display()),
+        ..Default::default()
+    }];
+    let res = run_processingmodule(&run_command, &|_| (), &module);
+    assert!(res.teardown.unwrap().success());
+    assert_eq!(res.services[0].exit_status, "stopped with SIGTERM");
+    let _ = std::fs::remove_file(&pid);
+
+    // Tests are skipped when a service doesn't start
+    module.services = vec![service("api", "exit 1")];
+    let res = run_processingmodule(&run_command, &|_| (), &module);
+    assert!(res.tests.is_none());
+    assert_eq!(
+        res.tests_skipped_reason(),
+        Some("Service 'api' failed to start".to_string())
+    );
+    assert!(!res.success());
+}
+
+#[test]
+fn t_retries() {
+    let dir = env::temp_dir().join(format!("fcheck-retries-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let count = dir.join("count");
+    let mut set = policy_module("true", TeardownPolicy::Always).tests.sets[0].clone();
+    // Succeeds on the third run
+    set.commands[0].cmd = format!("echo x >> {0}; test $(wc -l < {0}) -ge 3", count.display());
+    set.commands[0].retry = Some(Retry {
+        retries: Some(5),
+        delay: 10,
+        backoff: 2.0,
+        until: None,
+    });
+
+    let delays = Mutex::new(Vec::new());
+    let on_event = |event: &ProcessingEvent| {
+        if let ProcessingEvent::CommandRetrying(_, _, attempt, delay) = event {
+            delays.lock().unwrap().push((*attempt, delay.as_millis()));
+        }
+    };
+    let res = run_commandset(true, &run_command, &on_event, &Variables::new(), &set);
+    assert!(res.success());
+    assert_eq!(res.results[0].attempt_count(), 3);
+    assert_eq!(*delays.lock().unwrap(), vec![(1, 10), (2, 20)]);
+
+    // Every attempt fails, the last one is the result
+    set.commands[0].cmd = "echo attempt; exit 2".to_string();
+    set.commands[0].retry = Some(Retry {
+        retries: Some(2),
+        delay: 0,
+        backoff: 1.0,
+        until: None,
+    });
+    let res = run_commandset(true, &run_command, &|_| (), &Variables::new(), &set);
+    match &res.results[0] {
+        CommandResult::Retried { attempts, .. } => {
+            assert_eq!(attempts.len(), 3);
+            assert!(attempts.iter().all(|a| a.stdout() == "attempt\n"));
+        }
+        other => panic!("Expected retried attempts, got {:?}", other),
+    }
+    assert!(!res.success());
+
+    // Until stops retrying at the deadline
+    set.commands[0].retry = Some(Retry {
+        retries: None,
+        delay: 50,
+        backoff: 1.0,
+        until: Some(300),
+    });
+    let start = Instant::now();
+    let res = run_commandset(true, &run_command, &|_| (), &Variables::new(), &set);
+    assert!(start.elapsed() < Duration::from_millis(600));
+    assert!(res.results[0].attempt_count() >= 3);
+    assert!(!res.success());
+
+    // A command that succeeded but took longer than its max_duration is not retried
+    let _ = std::fs::remove_file(&count);
+    set.commands[0].cmd = format!("echo x >> {}; sleep 0.05", count.display());
+    set.commands[0].max_duration = Some(1);
+    set.commands[0].retry = Some(Retry {
+        retries: Some(3),
+        delay: 0,
+        backoff: 1.0,
+        until: None,
+    });
+    let res = run_commandset(true, &run_command, &|_| (), &Variables::new(), &set);
+    assert!(res.results[0].slow());
+    assert_eq!(res.results[0].attempt_count(), 1);
+    assert_eq!(std::fs::read_to_string(&count).unwrap(), "x\n");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn t_skipped_set() {
+    let mut set = policy_module("true", TeardownPolicy::Always).tests.sets[0].clone();
+    set.commands[0].cmd = "exit 1".to_string();
+    set.skip = Some("Disabled".to_string());
+
+    let skipped = Mutex::new(0);
+    let on_event = |event: &ProcessingEvent| match event {
+        ProcessingEvent::SetSkipped(_) => *skipped.lock().unwrap() += 1,
+        _ => panic!("Only a skipped event is expected, got {:?}", event),
+    };
+    let res = run_commandset(true, &run_command, &on_event, &Variables::new(), &set);
+    assert!(res.results.is_empty());
+    assert!(res.skipped());
+    assert!(res.success());
+    assert_eq!(*skipped.lock().unwrap(), 1);
+}
+
+#[test]
+fn t_run_all() {
+    let mut failing = policy_module("echo setup", TeardownPolicy::Always);
+    failing.tests.sets[0].commands[0].cmd = "sleep 0.2; exit 1".to_string();
+    let modules = vec![
+        ("slow.fcheck.toml".to_string(), failing),
+        (
+            "fast.fcheck.toml".to_string(),
+            policy_module("echo setup", TeardownPolicy::Always),
+        ),
+    ];
+
+    let finished = Mutex::new(Vec::new());
+    let on_event = |event: &RunEvent| {
+        if let RunEvent::ModuleFinished(res) = event {
+            finished.lock().unwrap().push(res.file.clone());
+        }
+    };
+    let res = run_all(&modules, 2, &LocalRunner, &on_event);
+
+    // Results are in the order of the files, whichever finished first
+    let files: Vec<_> = res.modules.iter().map(|m| m.file.as_str()).collect();
+    assert_eq!(files, vec!["slow.fcheck.toml", "fast.fcheck.toml"]);
+    assert_eq!(
+        *finished.lock().unwrap(),
+        vec!["fast.fcheck.toml", "slow.fcheck.toml"]
+    );
+    assert!(!res.modules[0].result.success());
+    assert!(res.modules[1].result.success());
+    assert!(!res.success());
+}
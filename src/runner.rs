@@ -0,0 +1,429 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::model::{
+    CommandResult, ExecutableCommand, ProcessingModule, ProcessingModuleResult, Stopwatch, Timing,
+};
+use crate::processor::run_command;
+
+// Runs the commands of a module. The processor decides which commands run and
+// in which order, the runner how each one of them is run.
+// Commands can be run from several threads when a CommandFamily is Parallel.
+pub trait CommandRunner: Sync {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult;
+
+    // Called before the setup of a module, and after its teardown
+    fn before_module(&self, _module: &ProcessingModule) {}
+    fn after_module(&self, _result: &ProcessingModuleResult) {}
+
+    // Services are processes as well, a runner that doesn't start processes
+    // doesn't start them
+    fn starts_services(&self) -> bool {
+        true
+    }
+
+    // The runner of the module teardown when it is not this one, so that
+    // cleanup still happens after a budget is used up
+    fn teardown_runner(&self) -> Option<&dyn CommandRunner> {
+        None
+    }
+}
+
+// A function can be used as the runner, mostly in tests
+impl<F> CommandRunner for F
+where
+    F: Fn(&ExecutableCommand) -> CommandResult + Sync,
+{
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        self(command)
+    }
+}
+
+// Runs every command in a subprocess
+pub struct LocalRunner;
+
+impl CommandRunner for LocalRunner {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        run_command(command)
+    }
+}
+
+// Reports the commands that would run without running them
+pub struct DryRunRunner;
+
+impl CommandRunner for DryRunRunner {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        CommandResult::NotRun {
+            command: command.clone(),
+            reason: "Dry run".to_string(),
+            aborted: false,
+            timing: Stopwatch::start().timing(),
+        }
+    }
+
+    fn starts_services(&self) -> bool {
+        false
+    }
+}
+
+// Runs commands with another runner until a wall-clock budget is used up.
+// The command running then is stopped like on a timeout, the commands after
+// it are not run, which fails them.
+pub struct BudgetRunner<'a> {
+    runner: &'a dyn CommandRunner,
+    deadline: Instant,
+    // Why the commands after the deadline are not run
+    reason: String,
+}
+
+impl<'a> BudgetRunner<'a> {
+    // The budget starts now
+    pub fn new(runner: &'a dyn CommandRunner, budget: Duration, reason: &str) -> BudgetRunner<'a> {
+        BudgetRunner {
+            runner,
+            deadline: Instant::now() + budget,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn used_up(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+impl CommandRunner for BudgetRunner<'_> {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return CommandResult::NotRun {
+                command: command.clone(),
+                reason: self.reason.clone(),
+                aborted: true,
+                timing: Stopwatch::start().timing(),
+            };
+        }
+        // The timeout of the command is kept when it ends before the budget.
+        // Rounded up, so a command stopped by the budget ends after it.
+        let left = left.as_millis() as u64 + 1;
+        let mut command = command.clone();
+        let own_timeout = matches!(command.timeout, Some(timeout) if timeout > 0 && timeout < left);
+        if !own_timeout {
+            command.timeout = Some(left);
+        }
+        match self.runner.run(&command) {
+            // Stopped by this budget, unless a budget of the runner is shorter
+            CommandResult::Timeout {
+                command,
+                stdout,
+                stderr,
+                signal,
+                reason: None,
+                timing,
+            } if !own_timeout => CommandResult::Timeout {
+                command,
+                stdout,
+                stderr,
+                signal,
+                reason: Some(self.reason.clone()),
+                timing,
+            },
+            res => res,
+        }
+    }
+
+    fn before_module(&self, module: &ProcessingModule) {
+        self.runner.before_module(module)
+    }
+
+    fn after_module(&self, result: &ProcessingModuleResult) {
+        self.runner.after_module(result)
+    }
+
+    fn starts_services(&self) -> bool {
+        self.runner.starts_services()
+    }
+
+    fn teardown_runner(&self) -> Option<&dyn CommandRunner> {
+        Some(self.runner.teardown_runner().unwrap_or(self.runner))
+    }
+}
+
+// What a command printed and how it ended, as saved by the RecordingRunner.
+// Assertions are not kept, they are checked again when the result is replayed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Recording {
+    cmd: String,
+    stdout: String,
+    stderr: String,
+    outcome: RecordedOutcome,
+    // Replayed results take as long as the recorded ones, without waiting
+    #[serde(default)]
+    duration_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum RecordedOutcome {
+    Exited { exit_code: u32 },
+    IrregularExitCode { exit_code: String },
+    Timeout { signal: String },
+    OsError { error: String },
+    RuntimeError { error: String },
+}
+
+impl Recording {
+    fn new(res: &CommandResult) -> Recording {
+        let outcome = match res {
+            CommandResult::StandardResult { exit_code, .. } => RecordedOutcome::Exited {
+                exit_code: *exit_code,
+            },
+            CommandResult::IrregularExitCode { exit_code, .. } => {
+                RecordedOutcome::IrregularExitCode {
+                    exit_code: exit_code.clone(),
+                }
+            }
+            CommandResult::Timeout { signal, .. } => RecordedOutcome::Timeout {
+                signal: signal.clone(),
+            },
+            CommandResult::OsError { error, .. } => RecordedOutcome::OsError {
+                error: error.clone(),
+            },
+            CommandResult::RuntimeError { error, .. } => RecordedOutcome::RuntimeError {
+                error: error.clone(),
+            },
+            // Not returned by the runners that can be recorded
+            _ => RecordedOutcome::RuntimeError {
+                error: "Result can't be recorded".to_string(),
+            },
+        };
+        Recording {
+            cmd: res.command().cmd.clone(),
+            stdout: res.stdout().to_string(),
+            stderr: res.stderr().to_string(),
+            outcome,
+            duration_ms: res.timing().duration.as_millis() as u64,
+        }
+    }
+
+    fn to_result(&self, command: &ExecutableCommand) -> CommandResult {
+        let command = command.clone();
+        let (stdout, stderr) = (self.stdout.clone(), self.stderr.clone());
+        let timing = Timing {
+            started: SystemTime::now(),
+            duration: Duration::from_millis(self.duration_ms),
+        };
+        match &self.outcome {
+            RecordedOutcome::Exited { exit_code } => CommandResult::StandardResult {
+                command,
+                stdout,
+                stderr,
+                exit_code: *exit_code,
+                assertions: Vec::new(),
+                timing,
+            },
+            RecordedOutcome::IrregularExitCode { exit_code } => CommandResult::IrregularExitCode {
+                command,
+                stdout,
+                stderr,
+                exit_code: exit_code.clone(),
+                timing,
+            },
+            RecordedOutcome::Timeout { signal } => CommandResult::Timeout {
+                command,
+                stdout,
+                stderr,
+                signal: signal.clone(),
+                // Set again by a BudgetRunner replaying under a budget
+                reason: None,
+                timing,
+            },
+            RecordedOutcome::OsError { error } => CommandResult::OsError {
+                command,
+                error: error.clone(),
+                timing,
+            },
+            RecordedOutcome::RuntimeError { error } => CommandResult::RuntimeError {
+                command,
+                stdout,
+                stderr,
+                error: error.clone(),
+                timing,
+            },
+        }
+    }
+}
+
+// Runs the commands with another runner and keeps their results, so the run
+// can be replayed later with the ReplayRunner
+pub struct RecordingRunner<R> {
+    runner: R,
+    recordings: Mutex<Vec<Recording>>,
+}
+
+impl<R: CommandRunner> RecordingRunner<R> {
+    pub fn new(runner: R) -> RecordingRunner<R> {
+        RecordingRunner {
+            runner,
+            recordings: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn recordings(&self) -> Vec<Recording> {
+        self.recordings
+            .lock()
+            .expect("Recording lock poisoned")
+            .clone()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&self.recordings())?;
+        fs::write(path, json)
+    }
+}
+
+impl<R: CommandRunner> CommandRunner for RecordingRunner<R> {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        let res = self.runner.run(command);
+        self.recordings
+            .lock()
+            .expect("Recording lock poisoned")
+            .push(Recording::new(&res));
+        res
+    }
+
+    fn before_module(&self, module: &ProcessingModule) {
+        self.runner.before_module(module)
+    }
+
+    fn after_module(&self, result: &ProcessingModuleResult) {
+        self.runner.after_module(result)
+    }
+
+    fn starts_services(&self) -> bool {
+        self.runner.starts_services()
+    }
+}
+
+// Returns the recorded results instead of running the commands. A command
+// that was run several times gets its recordings in the order they were made.
+pub struct ReplayRunner {
+    recordings: Mutex<BTreeMap<String, VecDeque<Recording>>>,
+}
+
+impl ReplayRunner {
+    pub fn new(recordings: Vec<Recording>) -> ReplayRunner {
+        let mut by_cmd: BTreeMap<String, VecDeque<Recording>> = BTreeMap::new();
+        for recording in recordings.into_iter() {
+            by_cmd
+                .entry(recording.cmd.clone())
+                .or_default()
+                .push_back(recording);
+        }
+        ReplayRunner {
+            recordings: Mutex::new(by_cmd),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<ReplayRunner> {
+        let json = fs::read_to_string(path)?;
+        let recordings: Vec<Recording> = serde_json::from_str(&json)?;
+        Ok(ReplayRunner::new(recordings))
+    }
+}
+
+impl CommandRunner for ReplayRunner {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        let recording = self
+            .recordings
+            .lock()
+            .expect("Recording lock poisoned")
+            .get_mut(&command.cmd)
+            .and_then(|r| r.pop_front());
+        match recording {
+            Some(recording) => recording.to_result(command),
+            None => CommandResult::RuntimeError {
+                command: command.clone(),
+                stdout: String::new(),
+                stderr: String::new(),
+                error: "No recorded result left for the command".to_string(),
+                timing: Stopwatch::start().timing(),
+            },
+        }
+    }
+
+    fn starts_services(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+fn t_record_and_replay() {
+    let runs = AtomicUsize::new(0);
+    let recorder = RecordingRunner::new(|command: &ExecutableCommand| {
+        let run = runs.fetch_add(1, Ordering::SeqCst) + 1;
+        match command.cmd.as_str() {
+            "sleep 10" => CommandResult::Timeout {
+                command: command.clone(),
+                stdout: String::new(),
+                stderr: "interrupted".to_string(),
+                signal: "SIGTERM".to_string(),
+                reason: None,
+                timing: Timing::default(),
+            },
+            _ => CommandResult::StandardResult {
+                command: command.clone(),
+                stdout: format!("run {}", run),
+                stderr: String::new(),
+                exit_code: 3,
+                assertions: Vec::new(),
+                timing: Timing::default(),
+            },
+        }
+    });
+    let cmd = |cmd: &str| ExecutableCommand {
+        cmd: cmd.to_string(),
+        ..Default::default()
+    };
+    for command in ["echo", "sleep 10", "echo"].iter() {
+        recorder.run(&cmd(command));
+    }
+
+    let path = std::env::temp_dir().join(format!("fcheck-recording-{}.json", std::process::id()));
+    recorder.save(&path).unwrap();
+    let replay = ReplayRunner::load(&path).unwrap();
+    let _ = fs::remove_file(&path);
+
+    // Each command gets its own recordings back in order
+    match replay.run(&cmd("echo")) {
+        CommandResult::StandardResult {
+            stdout, exit_code, ..
+        } => assert_eq!((stdout.as_str(), exit_code), ("run 1", 3)),
+        other => panic!("Expected a replayed result, got {:?}", other),
+    }
+    assert_eq!(replay.run(&cmd("echo")).stdout(), "run 3");
+    match replay.run(&cmd("sleep 10")) {
+        CommandResult::Timeout { stderr, signal, .. } => {
+            assert_eq!(
+                (stderr.as_str(), signal.as_str()),
+                ("interrupted", "SIGTERM")
+            )
+        }
+        other => panic!("Expected a replayed timeout, got {:?}", other),
+    }
+    match replay.run(&cmd("echo")) {
+        CommandResult::RuntimeError { error, .. } => {
+            assert_eq!(error, "No recorded result left for the command")
+        }
+        other => panic!("Expected no recording, got {:?}", other),
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
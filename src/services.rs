@@ -0,0 +1,401 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use subprocess::{ExitStatus, Popen};
+
+use crate::model::{
+    CommandResult, ExecutableCommand, ReadinessProbe, Service, ServiceResult, Stopwatch,
+};
+use crate::processor::{
+    popen_config, run_command, shell_command, stop_process, translate_error, ProcessGroup,
+    DEFAULT_GRACE_PERIOD_MS,
+};
+use crate::runner::CommandRunner;
+
+// A service running in the background while the tests run
+pub struct RunningService {
+    service: Service,
+    process: Mutex<Popen>,
+    // The service leads its own process group
+    pgid: Option<u32>,
+    _group: ProcessGroup,
+    stdout: Arc<Mutex<Vec<u8>>>,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+pub fn start_service(service: &Service) -> Result<RunningService, String> {
+    let (config, input) = popen_config(&service.command).map_err(translate_error)?;
+    let mut process =
+        Popen::create(&shell_command(&service.command), config).map_err(|e| e.to_string())?;
+    let pgid = process.pid();
+
+    if let (Some(input), Some(mut stdin)) = (input, process.stdin.take()) {
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    // Output is collected while the service runs, so the log probe can see it
+    let stdout = Arc::new(Mutex::new(Vec::new()));
+    let stderr = Arc::new(Mutex::new(Vec::new()));
+    let readers = vec![
+        collect_output(process.stdout.take(), stdout.clone()),
+        collect_output(process.stderr.take(), stderr.clone()),
+    ];
+
+    Ok(RunningService {
+        service: service.clone(),
+        process: Mutex::new(process),
+        pgid,
+        _group: ProcessGroup::new(pgid),
+        stdout,
+        stderr,
+        readers,
+    })
+}
+
+fn collect_output(redirect: Option<File>, buffer: Arc<Mutex<Vec<u8>>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut redirect = match redirect {
+            Some(redirect) => redirect,
+            None => return,
+        };
+        let mut chunk = [0; 4096];
+        while let Ok(n) = redirect.read(&mut chunk) {
+            if n == 0 {
+                break;
+            }
+            lock(&buffer).extend_from_slice(&chunk[..n]);
+        }
+    })
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl RunningService {
+    // Probes the service until it is ready, it exits or the startup timeout passes
+    pub fn wait_until_ready(&self) -> Result<(), String> {
+        let deadline = Instant::now() + Duration::from_millis(self.service.startup_timeout);
+        let interval = Duration::from_millis(self.service.probe_interval);
+        loop {
+            if let Some(status) = self.exit_status() {
+                return Err(format!("Exited before it was ready, {}", status));
+            }
+            let ready = match &self.service.ready {
+                Some(probe) => self.probe(probe, interval),
+                None => true,
+            };
+            if ready {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "Not ready after {}ms",
+                    self.service.startup_timeout
+                ));
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    fn probe(&self, probe: &ReadinessProbe, timeout: Duration) -> bool {
+        match probe {
+            ReadinessProbe::Tcp(address) => connect(address, timeout).is_some(),
+            ReadinessProbe::Http(url) => probe_http(url, timeout),
+            ReadinessProbe::Log(pattern) => match Regex::new(pattern) {
+                Ok(regex) => regex.is_match(&self.stdout()) || regex.is_match(&self.stderr()),
+                Err(_) => false,
+            },
+            ReadinessProbe::Command(command) => run_command(command).success(),
+        }
+    }
+
+    fn exit_status(&self) -> Option<String> {
+        lock(&self.process).poll().map(describe_exit_status)
+    }
+
+    // Name and exit status of the service, when it is no longer running
+    pub fn crashed(&self) -> Option<(String, String)> {
+        self.exit_status()
+            .map(|status| (self.service.name.clone(), status))
+    }
+
+    fn stdout(&self) -> String {
+        String::from_utf8_lossy(&lock(&self.stdout)).into_owned()
+    }
+
+    fn stderr(&self) -> String {
+        String::from_utf8_lossy(&lock(&self.stderr)).into_owned()
+    }
+
+    // Stops the process group of the service. `error` is why it failed to start.
+    pub fn stop(self, error: Option<String>) -> ServiceResult {
+        let grace_period = Duration::from_millis(
+            self.service
+                .command
+                .grace_period
+                .unwrap_or(DEFAULT_GRACE_PERIOD_MS),
+        );
+        let mut process = self.process.into_inner().unwrap_or_else(|e| e.into_inner());
+        let readers = self.readers;
+
+        let exited = process.poll().map(describe_exit_status);
+        // Also stops what the service started, when it already exited
+        let stopped = stop_process(&mut process, self.pgid, grace_period, &|| {
+            readers.iter().all(|r| r.is_finished())
+        });
+        for reader in readers {
+            let _ = reader.join();
+        }
+
+        let (exit_status, error) = match (exited, stopped) {
+            (Some(status), _) => {
+                let error = error.unwrap_or_else(|| format!("Stopped on its own, {}", status));
+                (status, Some(error))
+            }
+            (None, Ok(signal)) => (format!("stopped with {}", signal), error),
+            (None, Err(e)) => ("unknown".to_string(), error.or(Some(translate_error(e)))),
+        };
+
+        let stdout = String::from_utf8_lossy(&lock(&self.stdout)).into_owned();
+        let stderr = String::from_utf8_lossy(&lock(&self.stderr)).into_owned();
+        ServiceResult {
+            service: self.service,
+            error,
+            exit_status,
+            stdout,
+            stderr,
+        }
+    }
+}
+
+// Result of a service that could not be started at all
+pub fn failed_service(service: &Service, error: String) -> ServiceResult {
+    ServiceResult {
+        service: service.clone(),
+        error: Some(error),
+        exit_status: "not started".to_string(),
+        stdout: String::new(),
+        stderr: String::new(),
+    }
+}
+
+// Runs the commands of the tests while the services run
+pub struct ServicesRunner<'a> {
+    runner: &'a dyn CommandRunner,
+    services: &'a [RunningService],
+    teardown: Option<Box<ServicesRunner<'a>>>,
+}
+
+impl<'a> ServicesRunner<'a> {
+    pub fn new(
+        runner: &'a dyn CommandRunner,
+        services: &'a [RunningService],
+    ) -> ServicesRunner<'a> {
+        ServicesRunner {
+            runner,
+            services,
+            teardown: runner
+                .teardown_runner()
+                .map(|teardown| Box::new(ServicesRunner::new(teardown, services))),
+        }
+    }
+}
+
+impl CommandRunner for ServicesRunner<'_> {
+    fn run(&self, command: &ExecutableCommand) -> CommandResult {
+        run_with_services(self.runner, self.services, command)
+    }
+
+    fn teardown_runner(&self) -> Option<&dyn CommandRunner> {
+        self.teardown
+            .as_deref()
+            .map(|teardown| teardown as &dyn CommandRunner)
+    }
+}
+
+// Runs the command unless a service stopped, and checks the services again
+// once it finished, since they are what the command tests.
+fn run_with_services(
+    runner: &dyn CommandRunner,
+    services: &[RunningService],
+    command: &ExecutableCommand,
+) -> CommandResult {
+    let crashed = || services.iter().find_map(|s| s.crashed());
+    let crash_result = |(service, exit_status), stdout: &str, stderr: &str, timing| {
+        CommandResult::ServiceCrashed {
+            command: command.clone(),
+            service,
+            exit_status,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            timing,
+        }
+    };
+
+    if let Some(crash) = crashed() {
+        return crash_result(crash, "", "", Stopwatch::start().timing());
+    }
+    let res = runner.run(command);
+    match crashed() {
+        Some(crash) => crash_result(crash, res.stdout(), res.stderr(), res.timing()),
+        None => res,
+    }
+}
+
+fn describe_exit_status(status: ExitStatus) -> String {
+    match status {
+        ExitStatus::Exited(code) => format!("exited with code {}", code),
+        ExitStatus::Signaled(signal) => format!("killed by signal {}", signal),
+        ExitStatus::Other(code) => format!("exited with status {}", code),
+        ExitStatus::Undetermined => "exited".to_string(),
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> Option<TcpStream> {
+    address
+        .to_socket_addrs()
+        .ok()?
+        .find_map(|addr| TcpStream::connect_timeout(&addr, timeout).ok())
+}
+
+// A plain HTTP/1.0 GET, enough to check the status of a local URL
+fn probe_http(url: &str, timeout: Duration) -> bool {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let mut stream = match connect(&address, timeout) {
+        Some(stream) => stream,
+        None => return false,
+    };
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+    if stream.write_all(request.as_bytes()).is_err() {
+        return false;
+    }
+    let mut status_line = String::new();
+    if BufReader::new(stream).read_line(&mut status_line).is_err() {
+        return false;
+    }
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .map(|code| (200..300).contains(&code))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+use crate::model::Shell;
+
+#[cfg(test)]
+fn test_service(cmd: &str, ready: Option<ReadinessProbe>) -> Service {
+    Service {
+        name: "test".to_string(),
+        command: ExecutableCommand {
+            shell: Shell::default(),
+            cmd: cmd.to_string(),
+            grace_period: Some(500),
+            ..Default::default()
+        },
+        ready,
+        startup_timeout: 2000,
+        probe_interval: 20,
+    }
+}
+
+#[test]
+fn t_service_log_probe() {
+    let service = test_service(
+        "sleep 0.2; echo 'listening on 8080'; sleep 30",
+        Some(ReadinessProbe::Log("listening on \\d+".to_string())),
+    );
+
+    let running = start_service(&service).unwrap();
+    let start = Instant::now();
+    assert_eq!(running.wait_until_ready(), Ok(()));
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert_eq!(running.crashed(), None);
+
+    let res = running.stop(None);
+    assert!(res.success());
+    assert_eq!(res.exit_status, "stopped with SIGTERM");
+    assert_eq!(res.stdout, "listening on 8080\n");
+}
+
+#[test]
+fn t_service_exits_before_ready() {
+    let service = test_service(
+        "echo failed >&2; exit 3",
+        Some(ReadinessProbe::Log("never".to_string())),
+    );
+
+    let running = start_service(&service).unwrap();
+    let error = running.wait_until_ready().unwrap_err();
+    assert_eq!(error, "Exited before it was ready, exited with code 3");
+
+    let res = running.stop(Some(error));
+    assert!(!res.success());
+    assert_eq!(res.exit_status, "exited with code 3");
+    assert_eq!(res.stderr, "failed\n");
+}
+
+#[test]
+fn t_service_probes() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let status = if String::from_utf8_lossy(&request).starts_with("GET /health ") {
+                "200 OK"
+            } else {
+                "404 Not Found"
+            };
+            let _ = write!(stream, "HTTP/1.0 {}\r\n\r\n", status);
+        }
+    });
+
+    let timeout = Duration::from_millis(500);
+    assert!(connect(&address, timeout).is_some());
+    assert!(probe_http(&format!("http://{}/health", address), timeout));
+    assert!(!probe_http(&format!("http://{}/missing", address), timeout));
+
+    let running = start_service(&test_service("sleep 30", None)).unwrap();
+    assert!(running.probe(
+        &ReadinessProbe::Command(Box::new(test_service("exit 0", None).command)),
+        timeout
+    ));
+    assert!(!running.probe(
+        &ReadinessProbe::Command(Box::new(test_service("exit 1", None).command)),
+        timeout
+    ));
+    running.stop(None);
+}
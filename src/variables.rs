@@ -0,0 +1,314 @@
+use std::collections::BTreeSet;
+use std::env;
+
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+use crate::model::{
+    AssertionResult, Capture, CaptureExtract, CommandResult, ExecutableCommand, Variables,
+};
+
+// Replaces `${name}` with the value of a captured variable. References to
+// variables that were not captured, like `${HOME}`, are left to the shell.
+pub fn interpolate(text: &str, vars: &Variables) -> String {
+    if vars.is_empty() {
+        return text.to_string();
+    }
+    let reference = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("Valid regex");
+    reference
+        .replace_all(text, |caps: &Captures| match vars.get(&caps[1]) {
+            Some(value) => value.clone(),
+            None => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+// Resolves the references of a config when it is mapped:
+//   * `${NAME}` is a variable of the `[vars]` table
+//   * `${env:NAME}` is an environment variable, `${env:NAME:-default}` has a default
+//   * `${name}` of a captured variable is left for when the command runs
+//   * `$${...}` is a literal `${...}`, for the shell
+pub fn substitute(
+    text: &str,
+    vars: &Variables,
+    captures: &BTreeSet<String>,
+) -> Result<String, String> {
+    let reference = Regex::new(r"\$(\$)?\{([^}]*)\}").expect("Valid regex");
+    let mut error = None;
+    let result = reference.replace_all(text, |caps: &Captures| {
+        if caps.get(1).is_some() {
+            return caps[0][1..].to_string();
+        }
+        match resolve(&caps[2], vars, captures) {
+            Ok(value) => value,
+            Err(e) => {
+                error.get_or_insert(e);
+                String::new()
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(result.into_owned()),
+    }
+}
+
+fn resolve(
+    reference: &str,
+    vars: &Variables,
+    captures: &BTreeSet<String>,
+) -> Result<String, String> {
+    if let Some(env_ref) = reference.strip_prefix("env:") {
+        let (name, default) = match env_ref.find(":-") {
+            Some(i) => (&env_ref[..i], Some(&env_ref[i + 2..])),
+            None => (env_ref, None),
+        };
+        return match (env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => Ok(default.to_string()),
+            (Ok(value), _) => Ok(value),
+            (Err(_), Some(default)) => Ok(default.to_string()),
+            (Err(_), None) => Err(format!("Undefined environment variable '{}'", name)),
+        };
+    }
+    if !is_valid_name(reference) {
+        return Err(format!(
+            "Invalid variable reference '${{{}}}', write '$${{{}}}' to pass it to the shell",
+            reference, reference
+        ));
+    }
+    match vars.get(reference) {
+        Some(value) => Ok(value.clone()),
+        None if captures.contains(reference) => Ok(format!("${{{}}}", reference)),
+        None => Err(format!("Undefined variable '{}'", reference)),
+    }
+}
+
+pub fn with_variables(command: &ExecutableCommand, vars: &Variables) -> ExecutableCommand {
+    ExecutableCommand {
+        cmd: interpolate(&command.cmd, vars),
+        ..command.clone()
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// Captures the variable of a successful command. When nothing can be
+// captured, the command fails with a `capture` assertion.
+pub fn capture_output(res: CommandResult) -> (CommandResult, Option<(String, String)>) {
+    let capture = match &res.command().capture {
+        Some(capture) if res.success() => capture.clone(),
+        _ => return (res, None),
+    };
+    match (extract(&capture, res.stdout()), res) {
+        (Ok(value), res) => (res, Some((capture.name, value))),
+        (
+            Err(error),
+            CommandResult::StandardResult {
+                command,
+                stdout,
+                stderr,
+                exit_code,
+                mut assertions,
+                timing,
+            },
+        ) => {
+            assertions.push(AssertionResult {
+                assertion: format!("capture {}", capture.name),
+                expected: match &capture.extract {
+                    CaptureExtract::Stdout => "stdout".to_string(),
+                    CaptureExtract::Regex(pattern) => pattern.clone(),
+                    CaptureExtract::Json { path, .. } => path.clone(),
+                },
+                actual: error,
+                success: false,
+            });
+            let res = CommandResult::StandardResult {
+                command,
+                stdout,
+                stderr,
+                exit_code,
+                assertions,
+                timing,
+            };
+            (res, None)
+        }
+        (Err(_), res) => (res, None),
+    }
+}
+
+pub fn extract(capture: &Capture, stdout: &str) -> Result<String, String> {
+    let value = match &capture.extract {
+        CaptureExtract::Stdout => stdout.to_string(),
+        CaptureExtract::Regex(pattern) => {
+            let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+            let caps = regex
+                .captures(stdout)
+                .ok_or_else(|| "no match in stdout".to_string())?;
+            caps.get(1)
+                .or_else(|| caps.get(0))
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_default()
+        }
+        CaptureExtract::Json { pointer, .. } => {
+            let json = serde_json::from_str::<Value>(stdout)
+                .map_err(|e| format!("stdout is not valid JSON: {}", e))?;
+            match json.pointer(pointer) {
+                Some(Value::String(s)) => s.clone(),
+                Some(value) => value.to_string(),
+                None => return Err("(missing)".to_string()),
+            }
+        }
+    };
+    if capture.trim {
+        Ok(value.trim().to_string())
+    } else {
+        Ok(value)
+    }
+}
+
+#[test]
+fn t_interpolate() {
+    let mut vars = Variables::new();
+    assert_eq!(interpolate("echo ${token}", &vars), "echo ${token}");
+
+    vars.insert("token".to_string(), "abc".to_string());
+    vars.insert("id_2".to_string(), "7".to_string());
+    assert_eq!(
+        interpolate(
+            "curl -H 'Token: ${token}' /items/${id_2}?u=${HOME}$token",
+            &vars
+        ),
+        "curl -H 'Token: abc' /items/7?u=${HOME}$token"
+    );
+}
+
+#[test]
+fn t_extract() {
+    let capture = |trim, extract| Capture {
+        name: "v".to_string(),
+        trim,
+        extract,
+    };
+
+    assert_eq!(
+        extract(&capture(true, CaptureExtract::Stdout), "  abc\n"),
+        Ok("abc".to_string())
+    );
+    assert_eq!(
+        extract(&capture(false, CaptureExtract::Stdout), "abc\n"),
+        Ok("abc\n".to_string())
+    );
+    assert_eq!(
+        extract(
+            &capture(true, CaptureExtract::Regex(r"id=(\d+)".to_string())),
+            "name=x id=42\n"
+        ),
+        Ok("42".to_string())
+    );
+    assert_eq!(
+        extract(
+            &capture(true, CaptureExtract::Regex(r"\d+".to_string())),
+            "v1.2"
+        ),
+        Ok("1".to_string())
+    );
+    assert!(extract(
+        &capture(true, CaptureExtract::Regex(r"\d+".to_string())),
+        "none"
+    )
+    .is_err());
+
+    let json = |path: &str, pointer: &str| {
+        capture(
+            true,
+            CaptureExtract::Json {
+                path: path.to_string(),
+                pointer: pointer.to_string(),
+            },
+        )
+    };
+    let stdout = r#"{"token": "abc", "ids": [1, 2]}"#;
+    assert_eq!(
+        extract(&json("$.token", "/token"), stdout),
+        Ok("abc".to_string())
+    );
+    assert_eq!(
+        extract(&json("$.ids", "/ids"), stdout),
+        Ok("[1,2]".to_string())
+    );
+    assert_eq!(
+        extract(&json("$.missing", "/missing"), stdout),
+        Err("(missing)".to_string())
+    );
+}
+
+#[cfg(test)]
+use crate::model::Timing;
+
+#[test]
+fn t_capture_output() {
+    let command = ExecutableCommand {
+        cmd: "echo".to_string(),
+        capture: Some(Capture {
+            name: "id".to_string(),
+            trim: true,
+            extract: CaptureExtract::Regex(r"id=(\d+)".to_string()),
+        }),
+        ..Default::default()
+    };
+    let res = |stdout: &str| CommandResult::StandardResult {
+        command: command.clone(),
+        stdout: stdout.to_string(),
+        stderr: String::new(),
+        exit_code: 0,
+        assertions: Vec::new(),
+        timing: Timing::default(),
+    };
+
+    let (captured, value) = capture_output(res("id=5\n"));
+    assert!(captured.success());
+    assert_eq!(value, Some(("id".to_string(), "5".to_string())));
+
+    let (failed, value) = capture_output(res("nothing\n"));
+    assert!(!failed.success());
+    assert_eq!(value, None);
+}
+
+#[test]
+fn t_substitute() {
+    let mut vars = Variables::new();
+    vars.insert("host".to_string(), "localhost:8080".to_string());
+    let captures: BTreeSet<String> = vec!["token".to_string()].into_iter().collect();
+    let sub = |text: &str| substitute(text, &vars, &captures);
+
+    assert_eq!(
+        sub("curl ${host}/items -H ${token}"),
+        Ok("curl localhost:8080/items -H ${token}".to_string())
+    );
+    assert_eq!(
+        sub("echo $${HOME} $HOME"),
+        Ok("echo ${HOME} $HOME".to_string())
+    );
+    assert_eq!(sub("${env:PATH}"), Ok(env::var("PATH").unwrap_or_default()));
+    assert_eq!(
+        sub("${env:FCHECK_UNDEFINED_VAR:-/tmp}/data"),
+        Ok("/tmp/data".to_string())
+    );
+    assert_eq!(
+        sub("echo ${port}"),
+        Err("Undefined variable 'port'".to_string())
+    );
+    assert_eq!(
+        sub("${env:FCHECK_UNDEFINED_VAR}"),
+        Err("Undefined environment variable 'FCHECK_UNDEFINED_VAR'".to_string())
+    );
+    assert!(sub("echo ${#items[@]}").is_err());
+}